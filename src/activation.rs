@@ -1,5 +1,6 @@
 use serde::{Serialize, Deserialize};
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub enum Activation {
     Tanh,
@@ -8,6 +9,18 @@ pub enum Activation {
     ParametricReLU(f32),
     ELU(f32),
     Swish(f32),
+    #[serde(alias = "Linear")]
+    Identity,
+    Sigmoid,
+    GELU,
+    Mish,
+    SELU,
+    Softplus,
+    HardTanh,
+    HardSigmoid,
+    // Softmax and LogSoftmax act on the whole layer output, see apply and backward
+    Softmax,
+    LogSoftmax,
     // Add other activation functions if desired
 }
 
@@ -17,13 +30,25 @@ impl Activation {
             Activation::Tanh => x.tanh(),
             Activation::ReLU => x.max(0.0),
             Activation::LeakyReLU(alpha) => x.max(alpha * x),
-            Activation::ParametricReLU(alpha) => x.max(alpha * x),
+            // learned slopes are not bounded by 1, so unlike LeakyReLU it cannot be x.max(alpha * x)
+            Activation::ParametricReLU(alpha) => if x > 0.0 { x } else { alpha * x },
             Activation::ELU(alpha) => if x > 0.0 { x } else { alpha * (x.exp() - 1.0) },
             Activation::Swish(beta) => x * (beta * x).sigmoid(),
+            Activation::Identity => x,
+            Activation::Sigmoid => x.sigmoid(),
+            // tanh approximation of x * Phi(x)
+            Activation::GELU => 0.5 * x * (1.0 + (GELU_SCALE * (x + GELU_CUBIC * x.powi(3))).tanh()),
+            Activation::Mish => x * x.softplus().tanh(),
+            Activation::SELU => SELU_LAMBDA * if x > 0.0 { x } else { SELU_ALPHA * (x.exp() - 1.0) },
+            Activation::Softplus => x.softplus(),
+            Activation::HardTanh => x.clamp(-1.0, 1.0),
+            Activation::HardSigmoid => (x / 6.0 + 0.5).clamp(0.0, 1.0),
+            Activation::Softmax | Activation::LogSoftmax => panic!("{:?} acts on a whole layer, use Activation::apply", self),
             // Add other activation function computations if desired
         }
     }
 
+    // x is the pre-activation value (weighted sum plus bias), not the output of compute
     pub fn derivative(&self, x: f32) -> f32 {
         match self {
             Activation::Tanh => 1.0 - x.tanh().powi(2),
@@ -33,18 +58,190 @@ impl Activation {
             Activation::ELU(alpha) => if x > 0.0 { 1.0 } else { alpha * x.exp() },
             Activation::Swish(beta) => {
                 let sigmoid = (beta * x).sigmoid();
-                sigmoid + beta * x * (1.0 - sigmoid)
+                sigmoid + beta * x * sigmoid * (1.0 - sigmoid)
             }
+            Activation::Identity => 1.0,
+            Activation::Sigmoid => {
+                let sigmoid = x.sigmoid();
+                sigmoid * (1.0 - sigmoid)
+            }
+            Activation::GELU => {
+                let inner = GELU_SCALE * (x + GELU_CUBIC * x.powi(3));
+                let tanh = inner.tanh();
+                0.5 * (1.0 + tanh) + 0.5 * x * (1.0 - tanh * tanh) * GELU_SCALE * (1.0 + 3.0 * GELU_CUBIC * x * x)
+            }
+            Activation::Mish => {
+                let tanh = x.softplus().tanh();
+                tanh + x * (1.0 - tanh * tanh) * x.sigmoid()
+            }
+            Activation::SELU => SELU_LAMBDA * if x > 0.0 { 1.0 } else { SELU_ALPHA * x.exp() },
+            Activation::Softplus => x.sigmoid(),
+            Activation::HardTanh => if x > -1.0 && x < 1.0 { 1.0 } else { 0.0 },
+            Activation::HardSigmoid => if x > -3.0 && x < 3.0 { 1.0 / 6.0 } else { 0.0 },
+            Activation::Softmax | Activation::LogSoftmax => panic!("{:?} acts on a whole layer, use Activation::backward", self),
             // Add other activation function derivative computations if desired
         }
     }
+
+    // Derivative expressed through the output y = compute(x), for the functions where that
+    // saves recomputing exp/tanh. Returns None if the output alone does not determine it.
+    pub fn derivative_from_output(&self, y: f32) -> Option<f32> {
+        match self {
+            Activation::Tanh => Some(1.0 - y * y),
+            Activation::ReLU => Some(if y > 0.0 { 1.0 } else { 0.0 }),
+            Activation::LeakyReLU(alpha) if (0.0..=1.0).contains(alpha) => Some(if y > 0.0 { 1.0 } else { *alpha }),
+            Activation::ParametricReLU(alpha) if *alpha > 0.0 => Some(if y > 0.0 { 1.0 } else { *alpha }),
+            Activation::ELU(alpha) if *alpha > 0.0 => Some(if y > 0.0 { 1.0 } else { y + alpha }),
+            Activation::Identity => Some(1.0),
+            Activation::Sigmoid => Some(y * (1.0 - y)),
+            Activation::SELU => Some(if y > 0.0 { SELU_LAMBDA } else { y + SELU_LAMBDA * SELU_ALPHA }),
+            Activation::Softplus => Some(1.0 - (-y).exp()),
+            Activation::HardTanh => Some(if y > -1.0 && y < 1.0 { 1.0 } else { 0.0 }),
+            Activation::HardSigmoid => Some(if y > 0.0 && y < 1.0 { 1.0 / 6.0 } else { 0.0 }),
+            _ => None,
+        }
+    }
+
+    // Derivative at pre-activation x with output y, taking the cheaper output form where possible
+    pub fn gradient(&self, x: f32, y: f32) -> f32 {
+        self.derivative_from_output(y).unwrap_or_else(|| self.derivative(x))
+    }
+
+    // Applies the activation to a whole layer of pre-activations
+    pub fn apply(&self, xs: &[f32]) -> Vec<f32> {
+        match self {
+            Activation::Softmax => {
+                let max = xs.iter().fold(f32::NEG_INFINITY, |acc, x| acc.max(*x));
+                let exps: Vec<f32> = xs.iter().map(|x| (x - max).exp()).collect();
+                let sum: f32 = exps.iter().sum();
+                exps.iter().map(|e| e / sum).collect()
+            }
+            Activation::LogSoftmax => {
+                let max = xs.iter().fold(f32::NEG_INFINITY, |acc, x| acc.max(*x));
+                let log_sum = xs.iter().map(|x| (x - max).exp()).sum::<f32>().ln() + max;
+                xs.iter().map(|x| x - log_sum).collect()
+            }
+            _ => xs.iter().map(|x| self.compute(*x)).collect(),
+        }
+    }
+
+    // Gradient with respect to the pre-activations xs, given their outputs ys and the gradient with
+    // respect to the outputs. Element-wise activations use their derivative, Softmax and LogSoftmax
+    // multiply by their full Jacobian.
+    pub fn backward(&self, xs: &[f32], ys: &[f32], output_gradient: &[f32]) -> Vec<f32> {
+        match self {
+            Activation::Softmax => {
+                let dot: f32 = output_gradient.iter().zip(ys.iter()).map(|(g, y)| g * y).sum();
+                output_gradient.iter().zip(ys.iter()).map(|(g, y)| y * (g - dot)).collect()
+            }
+            Activation::LogSoftmax => {
+                let sum: f32 = output_gradient.iter().sum();
+                output_gradient.iter().zip(ys.iter()).map(|(g, y)| g - y.exp() * sum).collect()
+            }
+            _ => output_gradient.iter()
+                .zip(xs.iter().zip(ys.iter()))
+                .map(|(g, (x, y))| g * self.gradient(*x, *y))
+                .collect(),
+        }
+    }
 }
+const GELU_SCALE: f32 = 0.797_884_6; // sqrt(2 / pi)
+const GELU_CUBIC: f32 = 0.044715;
+const SELU_LAMBDA: f32 = 1.050_701;
+const SELU_ALPHA: f32 = 1.673_263_2;
+
 trait FloatSigmoid {
     fn sigmoid(self) -> Self;
+    fn softplus(self) -> Self;
 }
 
 impl FloatSigmoid for f32 {
     fn sigmoid(self) -> Self {
         1.0 / (1.0 + (-self).exp())
     }
+
+    // ln(1 + e^x) without overflowing for large x
+    fn softplus(self) -> Self {
+        self.max(0.0) + (-self.abs()).exp().ln_1p()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ELEMENT_WISE: [Activation; 17] = [
+        Activation::Tanh,
+        Activation::ReLU,
+        Activation::LeakyReLU(0.01),
+        Activation::ParametricReLU(0.25),
+        Activation::ELU(1.0),
+        Activation::Swish(1.0),
+        Activation::Identity,
+        Activation::Sigmoid,
+        Activation::GELU,
+        Activation::Mish,
+        Activation::SELU,
+        Activation::Softplus,
+        Activation::HardTanh,
+        Activation::HardSigmoid,
+        Activation::ELU(0.5),
+        Activation::Swish(2.0),
+        Activation::ParametricReLU(1.5),
+    ];
+
+    // Points where the derivative jumps, finite differences are meaningless right next to them
+    fn kinks(activation: &Activation) -> &'static [f32] {
+        match activation {
+            Activation::ReLU | Activation::LeakyReLU(_) | Activation::ParametricReLU(_)
+            | Activation::ELU(_) | Activation::SELU => &[0.0],
+            Activation::HardTanh => &[-1.0, 1.0],
+            Activation::HardSigmoid => &[-3.0, 3.0],
+            _ => &[],
+        }
+    }
+
+    #[test]
+    fn derivative_matches_finite_differences() {
+        let h = 1e-3;
+        for activation in ELEMENT_WISE {
+            for i in -400..=400 {
+                let x = i as f32 * 0.01 + 0.003;
+                if kinks(&activation).iter().any(|k| (x - k).abs() < 2.0 * h) {
+                    continue;
+                }
+                let numeric = (activation.compute(x + h) - activation.compute(x - h)) / (2.0 * h);
+                let analytic = activation.derivative(x);
+                assert!(
+                    (numeric - analytic).abs() < 1e-2 * analytic.abs().max(1.0),
+                    "{:?} at {}: finite difference {} vs derivative {}", activation, x, numeric, analytic
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn derivative_from_output_matches_derivative() {
+        for activation in ELEMENT_WISE {
+            for i in -400..=400 {
+                let x = i as f32 * 0.01 + 0.003;
+                if let Some(from_output) = activation.derivative_from_output(activation.compute(x)) {
+                    let analytic = activation.derivative(x);
+                    assert!(
+                        (from_output - analytic).abs() < 1e-3,
+                        "{:?} at {}: from output {} vs derivative {}", activation, x, from_output, analytic
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        for activation in ELEMENT_WISE.iter().chain([Activation::Softmax, Activation::LogSoftmax].iter()) {
+            let json = serde_json::to_string(activation).unwrap();
+            assert_eq!(&serde_json::from_str::<Activation>(&json).unwrap(), activation);
+        }
+        assert_eq!(serde_json::from_str::<Activation>("\"Linear\"").unwrap(), Activation::Identity);
+    }
 }
@@ -0,0 +1,201 @@
+use crate::game::{Bits, Board};
+use crate::loss::LossFunction;
+use crate::optimizer::OptimizerConfig;
+use crate::pretrain::PretrainConfig;
+use crate::train::TargetSync;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use std::fs::File;
+use std::io::BufReader;
+
+// Everything that shapes a DQN training run. Missing fields in a config file keep their default.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct TrainConfig {
+    pub game: GameKind,
+    // only used by GameKind::Board
+    pub board: BoardConfig,
+    pub episodes: usize,
+    pub discount_factor: f32,
+    pub initial_epsilon: f32,
+    pub final_epsilon: f32,
+    // epsilon is multiplied by this after every episode
+    pub epsilon_decay: f32,
+    pub buffer_capacity: usize,
+    pub batch_size: usize,
+    pub replay: ReplayConfig,
+    pub augmentation: Augmentation,
+    pub target_sync: TargetSync,
+    pub double_dqn: bool,
+    pub optimizer: OptimizerConfig,
+    pub loss: LossFunction,
+    pub rewards: Rewards,
+    // episodes between two progress lines
+    pub log_interval: usize,
+    // seeds network initialisation and self-play, None draws a fresh seed
+    pub seed: Option<u64>,
+    // supervised pretraining on minimax labels, see the pretrain module
+    pub pretrain: PretrainConfig,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum GameKind {
+    // an m,n,k game as set up by the board config, tic-tac-toe by default
+    Board,
+    ConnectFour,
+    UltimateTicTacToe,
+}
+
+// The m,n,k game to play: a width x height board, win_length in a row wins
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct BoardConfig {
+    pub width: usize,
+    pub height: usize,
+    pub win_length: usize,
+}
+
+impl Default for BoardConfig {
+    fn default() -> Self {
+        Self { width: 3, height: 3, win_length: 3 }
+    }
+}
+
+impl BoardConfig {
+    // B needs at least cells() bits
+    pub fn empty_board<B: Bits>(&self) -> Board<B> {
+        Board::new(self.width, self.height, self.win_length)
+    }
+
+    pub fn cells(&self) -> usize {
+        self.width * self.height
+    }
+
+    // The checks of Board::new, as an error instead of a panic
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.width == 0 || self.height == 0 {
+            return Err("board.width and board.height must be at least 1");
+        }
+        if self.win_length == 0 || self.win_length > self.width.max(self.height) {
+            return Err("board.win_length must be between 1 and the longer board side");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum ReplayConfig {
+    Uniform,
+    Prioritized { alpha: f32, beta: f32, beta_increment: f32 },
+}
+
+// How experiences use the symmetries of the board before they enter the replay buffer
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum Augmentation {
+    // store every experience as played
+    None,
+    // store all 8 rotated and reflected copies of every experience
+    Symmetries,
+    // store every experience in its canonical orientation and skip the ones already stored
+    Canonical,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Rewards {
+    pub illegal_move: f32,
+    pub win: f32,
+    // given to the move that allowed the opponent's winning reply
+    pub loss: f32,
+    // given to the drawing move and to the opponent's move before it
+    pub draw: f32,
+    // every move that does not end the game. Self-play targets negate the opponent's value, so a
+    // non-zero step reward changes sign from ply to ply.
+    pub step: f32,
+}
+
+impl Default for Rewards {
+    fn default() -> Self {
+        Self { illegal_move: -100.0, win: 10.0, loss: -10.0, draw: 0.0, step: 0.0 }
+    }
+}
+
+impl Default for TrainConfig {
+    fn default() -> Self {
+        Self {
+            game: GameKind::Board,
+            board: BoardConfig::default(),
+            episodes: 50000,
+            discount_factor: 0.9,
+            initial_epsilon: 1.0,
+            final_epsilon: 0.1,
+            epsilon_decay: 0.9999,
+            buffer_capacity: 10000,
+            batch_size: 300,
+            replay: ReplayConfig::Prioritized { alpha: 0.6, beta: 0.4, beta_increment: 0.00002 },
+            augmentation: Augmentation::None,
+            target_sync: TargetSync::Hard { interval: 100 },
+            double_dqn: true,
+            optimizer: OptimizerConfig::Adam { learning_rate: 0.001, weight_decay: 0.0 },
+            loss: LossFunction::Huber { delta: 1.0 },
+            rewards: Rewards::default(),
+            log_interval: 1000,
+            seed: None,
+            pretrain: PretrainConfig::default(),
+        }
+    }
+}
+
+impl TrainConfig {
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let file = File::open(path)?;
+        let reader = BufReader::new(file);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    // Sets one field given its dotted path, e.g. "rewards.win" or "optimizer.Adam.learning_rate".
+    // The value is read as JSON and taken as a plain string if it is not valid JSON.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = serde_json::to_value(&*self)?;
+        let mut field = &mut config;
+        for part in key.split('.') {
+            field = field.get_mut(part).ok_or_else(|| format!("Unknown config key: {}", key))?;
+        }
+        *field = serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()));
+        *self = serde_json::from_value(config).map_err(|e| format!("Invalid value for {}: {}", key, e))?;
+        Ok(())
+    }
+
+    // Builds the config from command line arguments: `--config <file>` loads a base config,
+    // every other `--key value` or `--key=value` overrides a single field (see set).
+    // Returns the config and the arguments that are not options.
+    pub fn from_args(args: &[String]) -> Result<(Self, Vec<String>), Box<dyn std::error::Error>> {
+        let mut options: Vec<(String, String)> = Vec::new();
+        let mut rest = Vec::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.strip_prefix("--") {
+                Some(option) => {
+                    let (key, value) = match option.split_once('=') {
+                        Some((key, value)) => (key.to_string(), value.to_string()),
+                        None => {
+                            let value = args.next().ok_or_else(|| format!("Missing value for --{}", option))?;
+                            (option.to_string(), value.clone())
+                        }
+                    };
+                    options.push((key, value));
+                }
+                None => rest.push(arg.clone()),
+            }
+        }
+
+        let mut config = match options.iter().find(|(key, _)| key == "config") {
+            Some((_, path)) => Self::load(path)?,
+            None => Self::default(),
+        };
+        for (key, value) in options.iter().filter(|(key, _)| key != "config") {
+            config.set(key, value)?;
+        }
+        Ok((config, rest))
+    }
+}
@@ -0,0 +1,318 @@
+use crate::game::{Game, Outcome, Player, Symmetry, cell_char};
+use std::fmt;
+
+pub const COLUMNS: usize = 7;
+pub const ROWS: usize = 6;
+
+// Bits per column in the bitboards: ROWS cells plus an always empty guard bit on top, so the
+// shifts of the win check cannot carry from one column into the next
+const COLUMN_BITS: usize = ROWS + 1;
+
+// Centre columns first, they take part in the most lines and make alpha-beta cut off early
+const SEARCH_ORDER: [usize; COLUMNS] = [3, 2, 4, 1, 5, 0, 6];
+
+// Above every score the evaluation can produce, wins are WIN plus the depth left, so sooner wins
+// score higher
+const WIN: i32 = 1_000_000;
+
+// Connect Four on the standard 6x7 board. The action is the column to drop a piece into, the
+// piece falls onto the lowest empty cell. Each player's pieces are a bitboard: bit
+// col * COLUMN_BITS + row, row 0 at the bottom.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ConnectFour {
+    // pieces of X and of O
+    pieces: [u64; 2],
+    heights: [usize; COLUMNS],
+    moves: usize,
+}
+
+impl Default for ConnectFour {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConnectFour {
+    pub fn new() -> Self {
+        Self { pieces: [0; 2], heights: [0; COLUMNS], moves: 0 }
+    }
+
+    // The piece at row (0 is the top, as displayed) and col
+    pub fn cell(&self, row: usize, col: usize) -> Option<Player> {
+        let bit = 1 << (col * COLUMN_BITS + ROWS - 1 - row);
+        if self.pieces[0] & bit != 0 {
+            Some(Player::X)
+        } else if self.pieces[1] & bit != 0 {
+            Some(Player::O)
+        } else {
+            None
+        }
+    }
+
+    pub fn winner(&self) -> Option<Player> {
+        if has_four(self.pieces[0]) {
+            Some(Player::X)
+        } else if has_four(self.pieces[1]) {
+            Some(Player::O)
+        } else {
+            None
+        }
+    }
+
+    fn player_index(player: Player) -> usize {
+        match player {
+            Player::X => 0,
+            Player::O => 1,
+        }
+    }
+}
+
+// Four in a row in any direction: shifting by 1 walks up a column, by COLUMN_BITS along a row
+// and by COLUMN_BITS -+ 1 along the diagonals. Two rounds of shift-and-and leave a bit for every
+// line of four.
+fn has_four(pieces: u64) -> bool {
+    [1, COLUMN_BITS, COLUMN_BITS - 1, COLUMN_BITS + 1].iter().any(|&shift| {
+        let pairs = pieces & (pieces >> shift);
+        pairs & (pairs >> (2 * shift)) != 0
+    })
+}
+
+impl Game for ConnectFour {
+    fn initial_state(&self) -> Self {
+        Self::new()
+    }
+
+    fn current_player(&self) -> Player {
+        if self.moves.is_multiple_of(2) { Player::X } else { Player::O }
+    }
+
+    fn legal_actions(&self) -> Vec<usize> {
+        (0..COLUMNS).filter(|&col| self.heights[col] < ROWS).collect()
+    }
+
+    fn is_legal(&self, action: usize) -> bool {
+        action < COLUMNS && self.heights[action] < ROWS
+    }
+
+    fn apply(&mut self, action: usize) -> Result<(), &'static str> {
+        if action >= COLUMNS {
+            return Err("Invalid move: out of bounds");
+        }
+        if self.heights[action] == ROWS {
+            return Err("Invalid move: column is full");
+        }
+        let player = Self::player_index(self.current_player());
+        self.pieces[player] |= 1 << (action * COLUMN_BITS + self.heights[action]);
+        self.heights[action] += 1;
+        self.moves += 1;
+        Ok(())
+    }
+
+    fn outcome(&self) -> Option<Outcome> {
+        match self.winner() {
+            Some(winner) => Some(Outcome::Win(winner)),
+            None if self.moves == ROWS * COLUMNS => Some(Outcome::Draw),
+            None => None,
+        }
+    }
+
+    fn action_space(&self) -> usize {
+        COLUMNS
+    }
+
+    // Like game::board_to_input: a plane of the player's pieces, then one of the opponent's,
+    // each ROWS x COLUMNS row-major with the top row first
+    fn observation(&self, player: Player) -> Vec<f32> {
+        let cells = ROWS * COLUMNS;
+        let mut input = vec![0.0; 2 * cells];
+        for row in 0..ROWS {
+            for col in 0..COLUMNS {
+                match self.cell(row, col) {
+                    None => (),
+                    Some(owner) if owner == player => input[row * COLUMNS + col] = 1.0,
+                    Some(_) => input[cells + row * COLUMNS + col] = 1.0,
+                }
+            }
+        }
+        input
+    }
+
+    // Gravity only allows mirroring left to right
+    fn symmetries(&self) -> Vec<Symmetry> {
+        vec![Symmetry::Identity, Symmetry::FlipHorizontal]
+    }
+
+    fn canonical_symmetry(&self) -> Symmetry {
+        let observation = self.observation(Player::X);
+        let mirrored = self.transform_observation(&observation, Symmetry::FlipHorizontal);
+        if mirrored < observation { Symmetry::FlipHorizontal } else { Symmetry::Identity }
+    }
+
+    fn transform_observation(&self, observation: &[f32], symmetry: Symmetry) -> Vec<f32> {
+        let mut transformed = observation.to_vec();
+        if symmetry == Symmetry::FlipHorizontal {
+            for row in transformed.chunks_exact_mut(COLUMNS) {
+                row.reverse();
+            }
+        }
+        transformed
+    }
+
+    fn transform_action(&self, action: usize, symmetry: Symmetry) -> usize {
+        if symmetry == Symmetry::FlipHorizontal { COLUMNS - 1 - action } else { action }
+    }
+}
+
+// One line per row from the top, cells separated by spaces
+impl fmt::Display for ConnectFour {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for row in 0..ROWS {
+            for col in 0..COLUMNS {
+                write!(f, "{} ", cell_char(self.cell(row, col)))?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+// Score of every legal column for the player to move by negamax with alpha-beta pruning,
+// searching depth plies and rating the positions there with evaluate. Scores above WIN are
+// forced wins, below -WIN forced losses.
+pub fn alpha_beta(game: &ConnectFour, depth: usize) -> Vec<(usize, i32)> {
+    let windows = windows();
+    SEARCH_ORDER.iter().copied()
+        .filter(|&col| game.is_legal(col))
+        .map(|col| {
+            let mut child = game.clone();
+            child.apply(col).expect("only legal columns are searched");
+            (col, -negamax(&child, depth.saturating_sub(1), -i32::MAX, i32::MAX, &windows))
+        })
+        .collect()
+}
+
+fn negamax(game: &ConnectFour, depth: usize, mut alpha: i32, beta: i32, windows: &[u64]) -> i32 {
+    // the previous move decided the game
+    if game.winner().is_some() {
+        return -(WIN + depth as i32);
+    }
+    if game.moves == ROWS * COLUMNS {
+        return 0;
+    }
+    if depth == 0 {
+        return evaluate(game, windows);
+    }
+
+    let mut best = -i32::MAX;
+    for col in SEARCH_ORDER.iter().copied().filter(|&col| game.is_legal(col)) {
+        let mut child = game.clone();
+        child.apply(col).expect("only legal columns are searched");
+        let value = -negamax(&child, depth - 1, -beta, -alpha, windows);
+        best = best.max(value);
+        alpha = alpha.max(value);
+        if alpha >= beta {
+            break;
+        }
+    }
+    best
+}
+
+// Static rating for the player to move: every line of four cells that only one player has
+// pieces in counts for that player, more pieces count disproportionately more
+fn evaluate(game: &ConnectFour, windows: &[u64]) -> i32 {
+    const WEIGHTS: [i32; 4] = [0, 1, 4, 16];
+    let me = ConnectFour::player_index(game.current_player());
+    let (mine, theirs) = (game.pieces[me], game.pieces[1 - me]);
+    windows.iter()
+        .map(|&window| {
+            let (own, other) = ((mine & window).count_ones() as usize, (theirs & window).count_ones() as usize);
+            match (own, other) {
+                (own, 0) => WEIGHTS[own.min(3)],
+                (0, other) => -WEIGHTS[other.min(3)],
+                _ => 0,
+            }
+        })
+        .sum()
+}
+
+// Bitboard masks of every line of four cells on the board
+fn windows() -> Vec<u64> {
+    let bit = |col: usize, row: usize| 1u64 << (col * COLUMN_BITS + row);
+    let mut windows = Vec::new();
+    for col in 0..COLUMNS {
+        for row in 0..ROWS {
+            for (d_col, d_row) in [(1, 0), (0, 1), (1, 1), (1, -1)] {
+                let end_col = col as isize + 3 * d_col;
+                let end_row = row as isize + 3 * d_row;
+                if end_col >= COLUMNS as isize || end_row < 0 || end_row >= ROWS as isize {
+                    continue;
+                }
+                windows.push((0..4).map(|step| bit((col as isize + step * d_col) as usize, (row as isize + step * d_row) as usize)).fold(0, |mask, cell| mask | cell));
+            }
+        }
+    }
+    windows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bit(col: usize, row: usize) -> u64 {
+        1 << (col * COLUMN_BITS + row)
+    }
+
+    fn pieces(cells: &[(usize, usize)]) -> u64 {
+        cells.iter().fold(0, |mask, &(col, row)| mask | bit(col, row))
+    }
+
+    fn play(actions: &[usize]) -> ConnectFour {
+        let mut game = ConnectFour::new();
+        for &action in actions {
+            game.apply(action).unwrap();
+        }
+        game
+    }
+
+    #[test]
+    fn finds_fours_in_every_direction() {
+        let lines: [[(usize, usize); 4]; 4] = [
+            [(2, 1), (2, 2), (2, 3), (2, 4)],
+            [(3, 5), (4, 5), (5, 5), (6, 5)],
+            [(0, 0), (1, 1), (2, 2), (3, 3)],
+            [(6, 1), (5, 2), (4, 3), (3, 4)],
+        ];
+        for line in lines {
+            assert!(has_four(pieces(&line)), "{:?}", line);
+            assert!(!has_four(pieces(&line[..3])), "{:?}", &line[..3]);
+        }
+    }
+
+    #[test]
+    fn lines_do_not_wrap_into_the_next_column() {
+        // the top three of column 0 and the bottom of column 1 are consecutive bits but for the guard
+        assert!(!has_four(pieces(&[(0, 3), (0, 4), (0, 5), (1, 0)])));
+        // diagonals leaving the board at the top or bottom do not continue on the other side
+        assert!(!has_four(pieces(&[(0, 3), (1, 4), (2, 5), (3, 0)])));
+        assert!(!has_four(pieces(&[(3, 2), (2, 1), (1, 0), (0, 5)])));
+    }
+
+    #[test]
+    fn apply_detects_wins() {
+        assert_eq!(play(&[0, 1, 0, 1, 0, 1, 0]).outcome(), Some(Outcome::Win(Player::X)));
+        assert_eq!(play(&[0, 0, 1, 1, 2, 2, 3]).outcome(), Some(Outcome::Win(Player::X)));
+        assert_eq!(play(&[6, 0, 1, 1, 2, 2, 3, 2, 3, 3, 6, 3]).outcome(), Some(Outcome::Win(Player::O)));
+        assert_eq!(play(&[0, 1, 0, 1, 0, 1]).outcome(), None);
+    }
+
+    #[test]
+    fn full_column_is_rejected() {
+        let mut game = play(&[0, 0, 0, 0, 0, 0]);
+        assert!(!game.is_legal(0));
+        assert!(!game.legal_actions().contains(&0));
+        let before = game.clone();
+        assert_eq!(game.apply(0), Err("Invalid move: column is full"));
+        assert_eq!(game, before);
+        assert_eq!(game.apply(COLUMNS), Err("Invalid move: out of bounds"));
+    }
+}
@@ -0,0 +1,321 @@
+use crate::connect_four::{self, ConnectFour};
+use crate::game::{Bits, Board, Game, Outcome, Player, Solver, board_to_input, heuristic_move, reachable_positions};
+use crate::network::NeuralNetwork;
+use crate::train::epsilon_greedy;
+use rand::{Rng, RngCore};
+use rand::seq::SliceRandom;
+use serde::{Serialize, Deserialize};
+
+// Anything the network can be evaluated against
+pub trait Opponent<G: Game> {
+    fn name(&self) -> String;
+
+    // A legal action for the player to move in game, which is not over
+    fn choose(&mut self, game: &G, rng: &mut dyn RngCore) -> usize;
+}
+
+pub struct RandomOpponent;
+
+impl<G: Game> Opponent<G> for RandomOpponent {
+    fn name(&self) -> String {
+        "random".to_string()
+    }
+
+    fn choose(&mut self, game: &G, rng: &mut dyn RngCore) -> usize {
+        *game.legal_actions().choose(rng).expect("a running game has legal actions")
+    }
+}
+
+// Another network, restricted to legal moves
+pub struct NetworkOpponent(pub NeuralNetwork);
+
+impl<G: Game> Opponent<G> for NetworkOpponent {
+    fn name(&self) -> String {
+        "network".to_string()
+    }
+
+    fn choose(&mut self, game: &G, mut rng: &mut dyn RngCore) -> usize {
+        let state = game.observation(game.current_player());
+        epsilon_greedy(&self.0, &state, 0.0, game, true, &mut rng)
+    }
+}
+
+// See game::play_heuristic_move
+pub struct HeuristicOpponent;
+
+impl<B: Bits> Opponent<Board<B>> for HeuristicOpponent {
+    fn name(&self) -> String {
+        "heuristic".to_string()
+    }
+
+    fn choose(&mut self, board: &Board<B>, _rng: &mut dyn RngCore) -> usize {
+        let (row, col) = heuristic_move(board, board.current_player()).expect("a running game has empty cells");
+        board.action(row, col)
+    }
+}
+
+// Perfect play, random among equally good moves
+#[derive(Default)]
+pub struct MinimaxOpponent<B: Bits = u16> {
+    solver: Solver<B>,
+}
+
+impl<B: Bits> Opponent<Board<B>> for MinimaxOpponent<B> {
+    fn name(&self) -> String {
+        "minimax".to_string()
+    }
+
+    fn choose(&mut self, board: &Board<B>, rng: &mut dyn RngCore) -> usize {
+        *self.solver.best_moves(board, board.current_player()).choose(rng).expect("a running game has empty cells")
+    }
+}
+
+// Connect Four baseline: alpha-beta search depth plies deep, random among the best columns
+pub struct AlphaBetaOpponent {
+    pub depth: usize,
+}
+
+impl Opponent<ConnectFour> for AlphaBetaOpponent {
+    fn name(&self) -> String {
+        format!("alphabeta:{}", self.depth)
+    }
+
+    fn choose(&mut self, game: &ConnectFour, rng: &mut dyn RngCore) -> usize {
+        let values = connect_four::alpha_beta(game, self.depth);
+        let best = values.iter().map(|&(_, value)| value).max().expect("a running game has legal columns");
+        let best_columns: Vec<usize> = values.into_iter().filter(|&(_, value)| value == best).map(|(col, _)| col).collect();
+        *best_columns.choose(rng).unwrap()
+    }
+}
+
+// Which side the evaluated network plays, X always moves first
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum Seat {
+    X,
+    O,
+    // X in even games, O in odd ones
+    Alternate,
+}
+
+// A share of the games with its 95% Wilson score interval
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Rate {
+    pub rate: f64,
+    pub lower: f64,
+    pub upper: f64,
+}
+
+impl Rate {
+    pub fn wilson(count: usize, total: usize) -> Self {
+        if total == 0 {
+            return Self { rate: 0.0, lower: 0.0, upper: 1.0 };
+        }
+        let z = 1.96;
+        let n = total as f64;
+        let p = count as f64 / n;
+        let denominator = 1.0 + z * z / n;
+        let center = (p + z * z / (2.0 * n)) / denominator;
+        let margin = z * (p * (1.0 - p) / n + z * z / (4.0 * n * n)).sqrt() / denominator;
+        Self { rate: p, lower: (center - margin).max(0.0), upper: (center + margin).min(1.0) }
+    }
+}
+
+// Results from the evaluated network's point of view. An illegal move by the network ends the
+// game and is counted only as illegal, not as a loss.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct EvalReport {
+    pub opponent: String,
+    pub seat: Seat,
+    pub games: usize,
+    pub wins: usize,
+    pub draws: usize,
+    pub losses: usize,
+    pub illegal_moves: usize,
+    pub win_rate: Rate,
+    pub draw_rate: Rate,
+    pub loss_rate: Rate,
+    pub illegal_rate: Rate,
+}
+
+impl EvalReport {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("EvalReport always serializes")
+    }
+}
+
+enum GameResult {
+    Win,
+    Draw,
+    Loss,
+    Illegal,
+}
+
+// Plays `games` greedy games of the network against opponent_kind, each from game.initial_state()
+pub fn evaluate<G: Game>(network: &NeuralNetwork, opponent_kind: &mut dyn Opponent<G>, game: &G, games: usize, seat: Seat, rng: &mut impl Rng) -> EvalReport {
+    let (mut wins, mut draws, mut losses, mut illegal_moves) = (0, 0, 0, 0);
+    for index in 0..games {
+        let network_player = match seat {
+            Seat::X => Player::X,
+            Seat::O => Player::O,
+            Seat::Alternate => if index % 2 == 0 { Player::X } else { Player::O },
+        };
+        match play_game(network, opponent_kind, game.initial_state(), network_player, rng) {
+            GameResult::Win => wins += 1,
+            GameResult::Draw => draws += 1,
+            GameResult::Loss => losses += 1,
+            GameResult::Illegal => illegal_moves += 1,
+        }
+    }
+
+    EvalReport {
+        opponent: opponent_kind.name(),
+        seat,
+        games,
+        wins,
+        draws,
+        losses,
+        illegal_moves,
+        win_rate: Rate::wilson(wins, games),
+        draw_rate: Rate::wilson(draws, games),
+        loss_rate: Rate::wilson(losses, games),
+        illegal_rate: Rate::wilson(illegal_moves, games),
+    }
+}
+
+fn play_game<G: Game>(network: &NeuralNetwork, opponent_kind: &mut dyn Opponent<G>, mut game: G, network_player: Player, rng: &mut impl Rng) -> GameResult {
+    loop {
+        let player = game.current_player();
+        if player == network_player {
+            let state = game.observation(player);
+            let action = epsilon_greedy(network, &state, 0.0, &game, false, rng);
+            if game.apply(action).is_err() {
+                return GameResult::Illegal;
+            }
+        } else {
+            let action = opponent_kind.choose(&game, rng);
+            game.apply(action).expect("opponents only play legal moves in a running game");
+        }
+
+        match game.outcome() {
+            Some(Outcome::Win(winner)) => return if winner == network_player { GameResult::Win } else { GameResult::Loss },
+            Some(Outcome::Draw) => return GameResult::Draw,
+            None => (),
+        }
+    }
+}
+
+// A position where the network's greedy move gives away game-theoretic value
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Blunder {
+    // one string per row, '-' for empty cells
+    pub board: Vec<String>,
+    pub player: Player,
+    pub chosen: usize,
+    pub optimal: Vec<usize>,
+    pub q_values: Vec<f32>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct AccuracyReport {
+    pub positions: usize,
+    // greedy move among the minimax-optimal ones
+    pub optimal: usize,
+    pub accuracy: f64,
+    // greedy move on an occupied cell
+    pub illegal: usize,
+    // Spearman rank correlation between the Q-values and the solver scores of the legal moves,
+    // averaged over the positions where both rankings are not constant
+    pub mean_rank_correlation: f64,
+    pub blunders: Vec<Blunder>,
+}
+
+impl AccuracyReport {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("AccuracyReport always serializes")
+    }
+}
+
+// Checks the greedy move of the network in every undecided position reachable from the empty
+// board against the moves that keep the minimax value
+pub fn move_accuracy<B: Bits>(network: &NeuralNetwork, board: &Board<B>, rng: &mut impl Rng) -> AccuracyReport {
+    let mut solver = Solver::new();
+    let positions = reachable_positions(board);
+    let mut optimal_count = 0;
+    let mut illegal = 0;
+    let mut correlations = Vec::new();
+    let mut blunders = Vec::new();
+
+    for (board, player) in &positions {
+        let state = board_to_input(board, *player);
+        let q_values = network.forward(&state);
+        let chosen = epsilon_greedy(network, &state, 0.0, board, false, rng);
+        let optimal = solver.optimal_moves(board, *player);
+
+        let legal = board.is_empty_cell(chosen);
+        if optimal.contains(&chosen) {
+            optimal_count += 1;
+        } else {
+            if !legal {
+                illegal += 1;
+            }
+            blunders.push(Blunder {
+                board: board.rows(),
+                player: *player,
+                chosen,
+                optimal,
+                q_values: q_values.clone(),
+            });
+        }
+
+        let (predicted, actual): (Vec<f64>, Vec<f64>) = solver.move_values(board, *player).into_iter()
+            .map(|(action, value)| (q_values[action] as f64, value as f64))
+            .unzip();
+        if let Some(correlation) = spearman(&predicted, &actual) {
+            correlations.push(correlation);
+        }
+    }
+
+    let mean_rank_correlation = if correlations.is_empty() { 0.0 } else { correlations.iter().sum::<f64>() / correlations.len() as f64 };
+    AccuracyReport {
+        positions: positions.len(),
+        optimal: optimal_count,
+        accuracy: optimal_count as f64 / positions.len() as f64,
+        illegal,
+        mean_rank_correlation,
+        blunders,
+    }
+}
+
+// Ranks starting at 1, tied values share their average rank
+fn ranks(values: &[f64]) -> Vec<f64> {
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    order.sort_by(|&a, &b| values[a].partial_cmp(&values[b]).unwrap());
+    let mut ranks = vec![0.0; values.len()];
+    let mut start = 0;
+    while start < order.len() {
+        let mut end = start;
+        while end + 1 < order.len() && values[order[end + 1]] == values[order[start]] {
+            end += 1;
+        }
+        let rank = (start + end) as f64 / 2.0 + 1.0;
+        for &index in &order[start..=end] {
+            ranks[index] = rank;
+        }
+        start = end + 1;
+    }
+    ranks
+}
+
+// Pearson correlation of the ranks, None when either side has no variance
+fn spearman(a: &[f64], b: &[f64]) -> Option<f64> {
+    let (ra, rb) = (ranks(a), ranks(b));
+    let n = ra.len() as f64;
+    let (mean_a, mean_b) = (ra.iter().sum::<f64>() / n, rb.iter().sum::<f64>() / n);
+    let covariance: f64 = ra.iter().zip(rb.iter()).map(|(x, y)| (x - mean_a) * (y - mean_b)).sum();
+    let variance_a: f64 = ra.iter().map(|x| (x - mean_a).powi(2)).sum();
+    let variance_b: f64 = rb.iter().map(|y| (y - mean_b).powi(2)).sum();
+    if variance_a == 0.0 || variance_b == 0.0 {
+        return None;
+    }
+    Some(covariance / (variance_a * variance_b).sqrt())
+}
@@ -1,53 +1,519 @@
-pub const BOARD_SIZE: usize = 3;
+use serde::{Serialize, Deserialize};
+use std::any::{Any, TypeId};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::{BitAnd, BitOr};
+use std::sync::{Mutex, OnceLock};
 
-pub type Board = Vec<Vec<char>>;
+// The two sides of a game, X moves first
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Player {
+    X,
+    O,
+}
 
-pub fn empty_board() -> Board {
-    vec![vec!['-'; BOARD_SIZE]; BOARD_SIZE]
+impl Player {
+    pub fn opponent(self) -> Player {
+        match self {
+            Player::X => Player::O,
+            Player::O => Player::X,
+        }
+    }
+
+    pub fn to_char(self) -> char {
+        match self {
+            Player::X => 'X',
+            Player::O => 'O',
+        }
+    }
+
+    pub fn from_char(c: char) -> Option<Player> {
+        match c {
+            'X' => Some(Player::X),
+            'O' => Some(Player::O),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Player {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_char())
+    }
+}
+
+impl From<Player> for char {
+    fn from(player: Player) -> char {
+        player.to_char()
+    }
+}
+
+impl TryFrom<char> for Player {
+    type Error = &'static str;
+
+    fn try_from(c: char) -> Result<Self, Self::Error> {
+        Player::from_char(c).ok_or("Not a player, expected 'X' or 'O'")
+    }
+}
+
+// A cell as displayed: the player's mark or '-' when empty
+pub fn cell_char(cell: Option<Player>) -> char {
+    cell.map_or('-', Player::to_char)
+}
+
+// How a finished game ended
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Outcome {
+    Win(Player),
+    Draw,
+}
+
+// A two-player, alternating, zero-sum game as the trainer and evaluator see it. Actions are
+// indices into a fixed action space the network has one output for, of which only the legal
+// ones may be applied.
+pub trait Game: Clone + fmt::Display + Send + Sync {
+    // A fresh game with the same rules, e.g. the same board size
+    fn initial_state(&self) -> Self;
+
+    // The player to move
+    fn current_player(&self) -> Player;
+
+    fn legal_actions(&self) -> Vec<usize>;
+
+    fn is_legal(&self, action: usize) -> bool {
+        self.legal_actions().contains(&action)
+    }
+
+    // One flag per action of the action space, true for the legal ones
+    fn action_mask(&self) -> Vec<bool> {
+        let mut mask = vec![false; self.action_space()];
+        for action in self.legal_actions() {
+            mask[action] = true;
+        }
+        mask
+    }
+
+    // Plays action for the current player. An illegal action leaves the game unchanged.
+    fn apply(&mut self, action: usize) -> Result<(), &'static str>;
+
+    // None while the game is still running
+    fn outcome(&self) -> Option<Outcome>;
+
+    // Number of actions, legal or not, and so of network outputs
+    fn action_space(&self) -> usize;
+
+    // Network input encoding the game from player's point of view
+    fn observation(&self, player: Player) -> Vec<f32>;
+
+    fn observation_size(&self) -> usize {
+        self.observation(Player::X).len()
+    }
+
+    // Transforms that map the game onto an equivalent one, see Symmetry. Games without
+    // symmetries only have the identity.
+    fn symmetries(&self) -> Vec<Symmetry> {
+        vec![Symmetry::Identity]
+    }
+
+    // The transform that brings the game into its canonical orientation
+    fn canonical_symmetry(&self) -> Symmetry {
+        Symmetry::Identity
+    }
+
+    // An observation of this game as it looks after the transform
+    fn transform_observation(&self, observation: &[f32], _symmetry: Symmetry) -> Vec<f32> {
+        observation.to_vec()
+    }
+
+    // An action of this game as it looks after the transform
+    fn transform_action(&self, action: usize, _symmetry: Symmetry) -> usize {
+        action
+    }
+}
+
+// The cell masks of a bitboard, bit i stands for cell i. The type bounds the number of cells.
+pub trait Bits: Copy + Default + Eq + Ord + Hash + fmt::Debug + Send + Sync + 'static
+    + BitAnd<Output = Self> + BitOr<Output = Self>
+{
+    const BITS: usize;
+
+    fn bit(index: usize) -> Self;
+
+    fn count_ones(self) -> u32;
+
+    fn is_zero(self) -> bool {
+        self == Self::default()
+    }
+}
+
+macro_rules! impl_bits {
+    ($($int:ty),*) => {$(
+        impl Bits for $int {
+            const BITS: usize = <$int>::BITS as usize;
+
+            fn bit(index: usize) -> Self {
+                1 << index
+            }
+
+            fn count_ones(self) -> u32 {
+                <$int>::count_ones(self)
+            }
+        }
+    )*};
+}
+
+impl_bits!(u16, u32, u64, u128);
+
+// 256 cells, enough for 15x15 Gomoku
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Wide([u64; 4]);
+
+impl BitAnd for Wide {
+    type Output = Wide;
+
+    fn bitand(self, other: Wide) -> Wide {
+        Wide(std::array::from_fn(|i| self.0[i] & other.0[i]))
+    }
+}
+
+impl BitOr for Wide {
+    type Output = Wide;
+
+    fn bitor(self, other: Wide) -> Wide {
+        Wide(std::array::from_fn(|i| self.0[i] | other.0[i]))
+    }
+}
+
+impl Bits for Wide {
+    const BITS: usize = 256;
+
+    fn bit(index: usize) -> Self {
+        let mut words = [0; 4];
+        words[index / 64] = 1 << (index % 64);
+        Wide(words)
+    }
+
+    fn count_ones(self) -> u32 {
+        self.0.iter().map(|word| word.count_ones()).sum()
+    }
+}
+
+// What every board of one shape shares: all cells, and every line of win_length cells
+pub struct ShapeMasks<B: Bits> {
+    full: B,
+    wins: Vec<B>,
+}
+
+impl<B: Bits> ShapeMasks<B> {
+    // The masks of a width x height board with win_length in a row, computed once per shape and
+    // mask type and kept for the rest of the run, so boards can refer to them and stay Copy
+    fn of(width: usize, height: usize, win_length: usize) -> &'static Self {
+        type Cache = HashMap<(TypeId, usize, usize, usize), &'static (dyn Any + Send + Sync)>;
+        static CACHE: OnceLock<Mutex<Cache>> = OnceLock::new();
+        let mut cache = CACHE.get_or_init(Default::default).lock().unwrap();
+        let masks = *cache.entry((TypeId::of::<B>(), width, height, win_length))
+            .or_insert_with(|| Box::leak(Box::new(Self::new(width, height, win_length))));
+        masks.downcast_ref().expect("cache entries are keyed by their mask type")
+    }
+
+    // Every line is walked from its first cell, in the directions right, down, down-right and
+    // down-left
+    fn new(width: usize, height: usize, win_length: usize) -> Self {
+        const DIRECTIONS: [(isize, isize); 4] = [(0, 1), (1, 0), (1, 1), (1, -1)];
+        let full = (0..width * height).fold(B::default(), |mask, cell| mask | B::bit(cell));
+        let k = win_length as isize;
+        let mut wins = Vec::new();
+        for row in 0..height as isize {
+            for col in 0..width as isize {
+                for (d_row, d_col) in DIRECTIONS {
+                    let (end_row, end_col) = (row + d_row * (k - 1), col + d_col * (k - 1));
+                    if end_row >= height as isize || end_col < 0 || end_col >= width as isize {
+                        continue;
+                    }
+                    wins.push((0..k).fold(B::default(), |mask, step| {
+                        mask | B::bit(((row + d_row * step) * width as isize + col + d_col * step) as usize)
+                    }));
+                }
+            }
+        }
+        Self { full, wins }
+    }
 }
 
-pub fn is_full(board: &Board) -> bool {
-    !board.iter().any(|row| row.iter().any(|&cell| cell == '-'))
+// An m,n,k game board: width x height cells, k = win_length in a row wins. The cells are
+// row-major, action = row * width + col, and each player's marks are one bitboard of type B,
+// which needs at least width * height bits. Copying a board copies two masks.
+#[derive(Copy, Clone)]
+pub struct Board<B: Bits = u16> {
+    width: usize,
+    height: usize,
+    win_length: usize,
+    x: B,
+    o: B,
+    masks: &'static ShapeMasks<B>,
+}
+
+impl<B: Bits> Board<B> {
+    pub fn new(width: usize, height: usize, win_length: usize) -> Self {
+        assert!(width > 0 && height > 0, "Board needs at least one cell");
+        assert!(width * height <= B::BITS, "Board has more cells than the mask type has bits");
+        assert!(win_length > 0 && win_length <= width.max(height), "Win length does not fit on the board");
+        Self { width, height, win_length, x: B::default(), o: B::default(), masks: ShapeMasks::of(width, height, win_length) }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn win_length(&self) -> usize {
+        self.win_length
+    }
+
+    // Number of cells, which is also the number of actions
+    pub fn size(&self) -> usize {
+        self.width * self.height
+    }
+
+    pub fn action(&self, row: usize, col: usize) -> usize {
+        row * self.width + col
+    }
+
+    pub fn position(&self, action: usize) -> (usize, usize) {
+        (action / self.width, action % self.width)
+    }
+
+    // The mark in cell action
+    pub fn cell(&self, action: usize) -> Option<Player> {
+        let bit = B::bit(action);
+        if !(self.x & bit).is_zero() {
+            Some(Player::X)
+        } else if !(self.o & bit).is_zero() {
+            Some(Player::O)
+        } else {
+            None
+        }
+    }
+
+    pub fn get(&self, row: usize, col: usize) -> Option<Player> {
+        self.cell(self.action(row, col))
+    }
+
+    // Puts player's mark into an empty cell, bounds and occupancy are the caller's to check
+    pub fn set(&mut self, row: usize, col: usize, player: Player) {
+        let bit = B::bit(self.action(row, col));
+        match player {
+            Player::X => self.x = self.x | bit,
+            Player::O => self.o = self.o | bit,
+        }
+    }
+
+    pub fn is_empty_cell(&self, action: usize) -> bool {
+        action < self.size() && (self.occupied() & B::bit(action)).is_zero()
+    }
+
+    // The mask of player's marks
+    pub fn marks(&self, player: Player) -> B {
+        match player {
+            Player::X => self.x,
+            Player::O => self.o,
+        }
+    }
+
+    fn occupied(&self) -> B {
+        self.x | self.o
+    }
+
+    fn empty_count(&self) -> usize {
+        self.size() - self.occupied().count_ones() as usize
+    }
+
+    // One string per row, '-' for empty cells
+    pub fn rows(&self) -> Vec<String> {
+        (0..self.height).map(|row| (0..self.width).map(|col| cell_char(self.get(row, col))).collect()).collect()
+    }
+
+    // The same shape without any marks
+    fn cleared(&self) -> Self {
+        Self { x: B::default(), o: B::default(), ..*self }
+    }
+
+    fn key(&self) -> (usize, usize, usize, B, B) {
+        (self.width, self.height, self.win_length, self.x, self.o)
+    }
+}
+
+impl<B: Bits> PartialEq for Board<B> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl<B: Bits> Eq for Board<B> {}
+
+impl<B: Bits> Hash for Board<B> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state);
+    }
+}
+
+impl<B: Bits> PartialOrd for Board<B> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<B: Bits> Ord for Board<B> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl<B: Bits> fmt::Debug for Board<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Board")
+            .field("win_length", &self.win_length)
+            .field("rows", &self.rows())
+            .finish()
+    }
 }
 
-pub fn check_winner(board: &Board) -> Option<char> {
-    for i in 0..BOARD_SIZE {
-        if board[i][0] != '-' && board[i][0] == board[i][1] && board[i][1] == board[i][2] {
-            return Some(board[i][0]);
+// m,n,k games (tic-tac-toe, Gomoku, ...), X moves first, so X is to move when both have placed
+// the same number of marks
+impl<B: Bits> Game for Board<B> {
+    fn initial_state(&self) -> Self {
+        self.cleared()
+    }
+
+    fn current_player(&self) -> Player {
+        if self.x.count_ones() == self.o.count_ones() { Player::X } else { Player::O }
+    }
+
+    fn legal_actions(&self) -> Vec<usize> {
+        (0..self.size()).filter(|&action| self.is_empty_cell(action)).collect()
+    }
+
+    fn is_legal(&self, action: usize) -> bool {
+        self.is_empty_cell(action)
+    }
+
+    fn apply(&mut self, action: usize) -> Result<(), &'static str> {
+        if action >= self.size() {
+            return Err("Invalid move: out of bounds");
         }
-        if board[0][i] != '-' && board[0][i] == board[1][i] && board[1][i] == board[2][i] {
-            return Some(board[0][i]);
+        let (row, col) = self.position(action);
+        let player = self.current_player();
+        make_move(self, player, row, col)
+    }
+
+    fn outcome(&self) -> Option<Outcome> {
+        match check_winner(self) {
+            Some(winner) => Some(Outcome::Win(winner)),
+            None if is_full(self) => Some(Outcome::Draw),
+            None => None,
         }
     }
-    if board[0][0] != '-' && board[0][0] == board[1][1] && board[1][1] == board[2][2] {
-        return Some(board[0][0]);
+
+    fn action_space(&self) -> usize {
+        self.size()
     }
-    if board[0][2] != '-' && board[0][2] == board[1][1] && board[1][1] == board[2][0] {
-        return Some(board[0][2]);
+
+    fn observation(&self, player: Player) -> Vec<f32> {
+        board_to_input(self, player)
+    }
+
+    fn symmetries(&self) -> Vec<Symmetry> {
+        Symmetry::of(self.width, self.height).to_vec()
+    }
+
+    fn canonical_symmetry(&self) -> Symmetry {
+        canonical_board(self).1
+    }
+
+    fn transform_observation(&self, observation: &[f32], symmetry: Symmetry) -> Vec<f32> {
+        transform_input(observation, symmetry, self.width, self.height)
     }
-    None
+
+    fn transform_action(&self, action: usize, symmetry: Symmetry) -> usize {
+        symmetry.map_action(action, self.width, self.height)
+    }
+}
+
+// One line per row, cells separated by spaces
+impl<B: Bits> fmt::Display for Board<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for row in 0..self.height {
+            for col in 0..self.width {
+                write!(f, "{} ", cell_char(self.get(row, col)))?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+// Two planes of width x height cells: the player's marks, then the opponent's
+pub fn board_to_input<B: Bits>(board: &Board<B>, player: Player) -> Vec<f32> {
+    let total_size = board.size();
+    let mut input = vec![0.0; total_size * 2];
+    for idx in 0..total_size {
+        match board.cell(idx) {
+            Some(owner) if owner == player => input[idx] = 1.0,
+            Some(_) => input[total_size + idx] = 1.0,
+            None => (),
+        }
+    }
+    input
+}
+
+// Applies a board transform to a network input made of planes of width x height cells, like
+// board_to_input's
+pub fn transform_input(input: &[f32], symmetry: Symmetry, width: usize, height: usize) -> Vec<f32> {
+    let cells = width * height;
+    let mut transformed = vec![0.0; input.len()];
+    for (plane, transformed_plane) in input.chunks_exact(cells).zip(transformed.chunks_exact_mut(cells)) {
+        for (cell, value) in plane.iter().enumerate() {
+            transformed_plane[symmetry.map_action(cell, width, height)] = *value;
+        }
+    }
+    transformed
+}
+
+// The classic 3x3 board with three in a row
+pub fn empty_board() -> Board {
+    Board::new(3, 3, 3)
 }
 
-pub fn make_move(board: &mut Board, player: char, row: usize, col: usize) -> Result<(), &'static str> {
-    if row >= BOARD_SIZE || col >= BOARD_SIZE {
+pub fn is_full<B: Bits>(board: &Board<B>) -> bool {
+    board.occupied() == board.masks.full
+}
+
+// The player owning every cell of one of the precomputed lines of win_length cells
+pub fn check_winner<B: Bits>(board: &Board<B>) -> Option<Player> {
+    [Player::X, Player::O].into_iter().find(|&player| {
+        let marks = board.marks(player);
+        board.masks.wins.iter().any(|&line| marks & line == line)
+    })
+}
+
+pub fn make_move<B: Bits>(board: &mut Board<B>, player: Player, row: usize, col: usize) -> Result<(), &'static str> {
+    if row >= board.height || col >= board.width {
         return Err("Invalid move: out of bounds");
     }
-    if board[row][col] != '-' {
+    if board.get(row, col).is_some() {
         return Err("Invalid move: cell already occupied");
     }
 
-    board[row][col] = player;
+    board.set(row, col, player);
     Ok(())
 }
 
-pub fn play_random_move(board: &mut Board, player: char) -> Result<(), &'static str> {
-    let available_moves: Vec<(usize, usize)> = board.iter().enumerate().flat_map(|(row, r)| {
-        r.iter().enumerate().filter_map(move |(col, &cell)| if cell == '-' {
-            Some((row, col))
-        } else {
-            None
-        })
-    }).collect();
+pub fn play_random_move<B: Bits>(board: &mut Board<B>, player: Player) -> Result<(), &'static str> {
+    let available_moves = empty_cells(board);
 
     if available_moves.is_empty() {
         return Err("No valid moves available");
@@ -56,4 +522,403 @@ pub fn play_random_move(board: &mut Board, player: char) -> Result<(), &'static
     let idx = rand::random::<usize>() % available_moves.len();
     let (row, col) = available_moves[idx];
     make_move(board, player, row, col)
-}
\ No newline at end of file
+}
+
+fn empty_cells<B: Bits>(board: &Board<B>) -> Vec<(usize, usize)> {
+    (0..board.size()).filter(|&action| board.is_empty_cell(action)).map(|action| board.position(action)).collect()
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Bound {
+    Exact,
+    // the real value is at least the stored one (the search failed high)
+    Lower,
+    // the real value is at most the stored one (the search failed low)
+    Upper,
+}
+
+// Perfect play by negamax search with alpha-beta pruning and a transposition table. Searches the
+// whole game tree, so beyond 3x3 it is only practical for small boards.
+// Scores are from the view of the player to move: 0 is a draw, a win scores the number of empty
+// cells left after the winning move plus one, so quicker wins score higher, and a loss is the
+// negated score of the opponent's win. Only the sign is the game-theoretic value.
+#[derive(Default)]
+pub struct Solver<B: Bits = u16> {
+    table: HashMap<(Board<B>, Player), (i32, Bound)>,
+}
+
+impl<B: Bits> Solver<B> {
+    pub fn new() -> Self {
+        Self { table: HashMap::new() }
+    }
+
+    // Score of the position for player, who is to move
+    pub fn value(&mut self, board: &Board<B>, player: Player) -> i32 {
+        self.negamax(*board, player, -i32::MAX, i32::MAX)
+    }
+
+    // Score of every legal move (row * width + col) for player, who is to move
+    pub fn move_values(&mut self, board: &Board<B>, player: Player) -> Vec<(usize, i32)> {
+        empty_cells(board).into_iter()
+            .map(|(row, col)| {
+                let mut next = *board;
+                next.set(row, col, player);
+                let value = if check_winner(&next).is_some() {
+                    next.empty_count() as i32 + 1
+                } else if is_full(&next) {
+                    0
+                } else {
+                    -self.negamax(next, player.opponent(), -i32::MAX, i32::MAX)
+                };
+                (board.action(row, col), value)
+            })
+            .collect()
+    }
+
+    // Moves with the best score, i.e. the quickest win, or the slowest loss
+    pub fn best_moves(&mut self, board: &Board<B>, player: Player) -> Vec<usize> {
+        let values = self.move_values(board, player);
+        let best = values.iter().map(|&(_, value)| value).max().unwrap_or(0);
+        values.into_iter().filter(|&(_, value)| value == best).map(|(action, _)| action).collect()
+    }
+
+    // Moves that keep the game-theoretic value (win, draw or loss) of the position
+    pub fn optimal_moves(&mut self, board: &Board<B>, player: Player) -> Vec<usize> {
+        let values = self.move_values(board, player);
+        let best = values.iter().map(|&(_, value)| value.signum()).max().unwrap_or(0);
+        values.into_iter().filter(|&(_, value)| value.signum() == best).map(|(action, _)| action).collect()
+    }
+
+    fn negamax(&mut self, board: Board<B>, player: Player, mut alpha: i32, mut beta: i32) -> i32 {
+        // the previous move decided the game
+        if check_winner(&board).is_some() {
+            return -(board.empty_count() as i32 + 1);
+        }
+        if is_full(&board) {
+            return 0;
+        }
+
+        let key = (board, player);
+        if let Some(&(value, bound)) = self.table.get(&key) {
+            match bound {
+                Bound::Exact => return value,
+                Bound::Lower => alpha = alpha.max(value),
+                Bound::Upper => beta = beta.min(value),
+            }
+            if alpha >= beta {
+                return value;
+            }
+        }
+
+        let original_alpha = alpha;
+        let mut best = -i32::MAX;
+        for (row, col) in empty_cells(&board) {
+            let mut next = board;
+            next.set(row, col, player);
+            let value = -self.negamax(next, player.opponent(), -beta, -alpha);
+            best = best.max(value);
+            alpha = alpha.max(value);
+            if alpha >= beta {
+                break;
+            }
+        }
+
+        let bound = if best <= original_alpha {
+            Bound::Upper
+        } else if best >= beta {
+            Bound::Lower
+        } else {
+            Bound::Exact
+        };
+        self.table.insert(key, (best, bound));
+        best
+    }
+}
+
+// Plays one of the best moves for player. With random_tie_break the move is drawn among all
+// equally good moves, otherwise the first one is taken.
+pub fn play_minimax_move<B: Bits>(board: &mut Board<B>, player: Player, solver: &mut Solver<B>, random_tie_break: bool) -> Result<(), &'static str> {
+    let best_moves = solver.best_moves(board, player);
+    if best_moves.is_empty() {
+        return Err("No valid moves available");
+    }
+
+    let idx = if random_tie_break { rand::random::<usize>() % best_moves.len() } else { 0 };
+    let (row, col) = board.position(best_moves[idx]);
+    make_move(board, player, row, col)
+}
+
+// Rule-of-thumb player: completes its own line, else blocks the opponent's, else takes the
+// centre, a corner or any cell, in that order
+pub fn play_heuristic_move<B: Bits>(board: &mut Board<B>, player: Player) -> Result<(), &'static str> {
+    let (row, col) = heuristic_move(board, player).ok_or("No valid moves available")?;
+    make_move(board, player, row, col)
+}
+
+// The cell play_heuristic_move takes, None on a full board
+pub fn heuristic_move<B: Bits>(board: &Board<B>, player: Player) -> Option<(usize, usize)> {
+    let cells = empty_cells(board);
+    if cells.is_empty() {
+        return None;
+    }
+
+    let completes_line = |board: &Board<B>, mover: Player, (row, col): (usize, usize)| {
+        let mut board = *board;
+        board.set(row, col, mover);
+        check_winner(&board) == Some(mover)
+    };
+    let center = (board.height / 2, board.width / 2);
+    let corners: Vec<(usize, usize)> = cells.iter().copied()
+        .filter(|&(row, col)| (row == 0 || row == board.height - 1) && (col == 0 || col == board.width - 1))
+        .collect();
+
+    let cell = cells.iter().copied().find(|&cell| completes_line(board, player, cell))
+        .or_else(|| cells.iter().copied().find(|&cell| completes_line(board, player.opponent(), cell)))
+        .or_else(|| cells.contains(&center).then_some(center))
+        .or_else(|| (!corners.is_empty()).then(|| corners[rand::random::<usize>() % corners.len()]))
+        .unwrap_or_else(|| cells[rand::random::<usize>() % cells.len()]);
+    Some(cell)
+}
+
+// Every position reachable from the given empty board with X moving first that is not decided
+// yet, with the player to move. Each position is listed once. Only feasible for small boards.
+pub fn reachable_positions<B: Bits>(board: &Board<B>) -> Vec<(Board<B>, Player)> {
+    let mut seen = HashSet::new();
+    let mut positions = Vec::new();
+    let mut stack = vec![(*board, Player::X)];
+    while let Some((board, player)) = stack.pop() {
+        if check_winner(&board).is_some() || is_full(&board) || !seen.insert(board) {
+            continue;
+        }
+        for (row, col) in empty_cells(&board) {
+            let mut next = board;
+            next.set(row, col, player);
+            stack.push((next, player.opponent()));
+        }
+        positions.push((board, player));
+    }
+    positions
+}
+
+// The 8 rotations and reflections of the board (the dihedral group D4). Each maps a position to
+// an equivalent one: same value, with the moves mapped the same way. Rotations by 90 degrees and
+// the diagonal mirrors swap width and height, so a non-square board only maps onto itself under
+// the other four.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Symmetry {
+    Identity,
+    // clockwise
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    // mirror left to right
+    FlipHorizontal,
+    // mirror top to bottom
+    FlipVertical,
+    // mirror along the main diagonal
+    Transpose,
+    // mirror along the anti-diagonal
+    AntiTranspose,
+}
+
+impl Symmetry {
+    pub const ALL: [Symmetry; 8] = [
+        Symmetry::Identity, Symmetry::Rotate90, Symmetry::Rotate180, Symmetry::Rotate270,
+        Symmetry::FlipHorizontal, Symmetry::FlipVertical, Symmetry::Transpose, Symmetry::AntiTranspose,
+    ];
+
+    // The transforms that map a width x height board onto a board of the same shape
+    pub fn of(width: usize, height: usize) -> &'static [Symmetry] {
+        if width == height {
+            &Self::ALL
+        } else {
+            &[Symmetry::Identity, Symmetry::Rotate180, Symmetry::FlipHorizontal, Symmetry::FlipVertical]
+        }
+    }
+
+    // The transform that undoes this one
+    pub fn inverse(self) -> Symmetry {
+        match self {
+            Symmetry::Rotate90 => Symmetry::Rotate270,
+            Symmetry::Rotate270 => Symmetry::Rotate90,
+            symmetry => symmetry,
+        }
+    }
+
+    pub fn swaps_dimensions(self) -> bool {
+        matches!(self, Symmetry::Rotate90 | Symmetry::Rotate270 | Symmetry::Transpose | Symmetry::AntiTranspose)
+    }
+
+    // Where the cell (row, col) of a width x height board ends up
+    pub fn map_cell(self, row: usize, col: usize, width: usize, height: usize) -> (usize, usize) {
+        let (last_row, last_col) = (height - 1, width - 1);
+        match self {
+            Symmetry::Identity => (row, col),
+            Symmetry::Rotate90 => (col, last_row - row),
+            Symmetry::Rotate180 => (last_row - row, last_col - col),
+            Symmetry::Rotate270 => (last_col - col, row),
+            Symmetry::FlipHorizontal => (row, last_col - col),
+            Symmetry::FlipVertical => (last_row - row, col),
+            Symmetry::Transpose => (col, row),
+            Symmetry::AntiTranspose => (last_col - col, last_row - row),
+        }
+    }
+
+    // Where the action (a row-major cell index) of a width x height board ends up, as an action
+    // of the transformed board
+    pub fn map_action(self, action: usize, width: usize, height: usize) -> usize {
+        let (row, col) = self.map_cell(action / width, action % width, width, height);
+        let transformed_width = if self.swaps_dimensions() { height } else { width };
+        row * transformed_width + col
+    }
+
+    pub fn apply<B: Bits>(self, board: &Board<B>) -> Board<B> {
+        let mut transformed = if self.swaps_dimensions() && board.width != board.height {
+            Board::new(board.height, board.width, board.win_length)
+        } else {
+            board.cleared()
+        };
+        let transformed_width = transformed.width;
+        for action in 0..board.size() {
+            if let Some(player) = board.cell(action) {
+                let target = self.map_action(action, board.width, board.height);
+                transformed.set(target / transformed_width, target % transformed_width, player);
+            }
+        }
+        transformed
+    }
+}
+
+// The representative of the board's symmetry class, the lexicographically smallest of the
+// transformed boards of the same shape, and the transform that produces it. Equivalent boards
+// share the representative, so it can key tables and deduplicate positions.
+pub fn canonical_board<B: Bits>(board: &Board<B>) -> (Board<B>, Symmetry) {
+    Symmetry::of(board.width, board.height).iter()
+        .map(|&symmetry| (symmetry.apply(board), symmetry))
+        .min_by(|a, b| a.0.cmp(&b.0))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Board from rows of 'X', 'O' and '-'
+    fn board(rows: &[&str]) -> Board {
+        let mut board = Board::new(rows[0].len(), rows.len(), 3);
+        for (row, cells) in rows.iter().enumerate() {
+            for (col, cell) in cells.chars().enumerate() {
+                if let Some(player) = Player::from_char(cell) {
+                    board.set(row, col, player);
+                }
+            }
+        }
+        board
+    }
+
+    // Win, draw or loss for player to move by plain minimax, without pruning or a table
+    fn plain_value(board: &Board, player: Player) -> i32 {
+        (0..board.size())
+            .filter(|&action| board.is_empty_cell(action))
+            .map(|action| {
+                let (row, col) = board.position(action);
+                let mut next = *board;
+                next.set(row, col, player);
+                if check_winner(&next).is_some() {
+                    1
+                } else if is_full(&next) {
+                    0
+                } else {
+                    -plain_value(&next, player.opponent())
+                }
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn empty_board_is_a_draw() {
+        assert_eq!(Solver::new().value(&empty_board(), Player::X), 0);
+    }
+
+    #[test]
+    fn takes_the_win_and_blocks_the_loss() {
+        let mut solver = Solver::new();
+        let win = board(&["XX-", "OO-", "---"]);
+        assert_eq!(solver.best_moves(&win, Player::X), vec![2]);
+        assert!(solver.optimal_moves(&win, Player::X).contains(&2));
+        assert!(solver.value(&win, Player::X) > 0);
+
+        let block = board(&["XX-", "-O-", "---"]);
+        assert_eq!(solver.optimal_moves(&block, Player::O), vec![2]);
+    }
+
+    #[test]
+    fn move_values_agree_with_plain_minimax() {
+        // one solver for all positions, so table entries from earlier searches with other
+        // windows are reused. From two marks on the plain search stays quick.
+        let mut solver = Solver::new();
+        for (board, player) in reachable_positions(&empty_board()).into_iter().filter(|(board, _)| board.empty_count() < 8) {
+            for (action, value) in solver.move_values(&board, player) {
+                let (row, col) = board.position(action);
+                let mut next = board;
+                next.set(row, col, player);
+                let expected = if check_winner(&next).is_some() {
+                    1
+                } else if is_full(&next) {
+                    0
+                } else {
+                    -plain_value(&next, player.opponent())
+                };
+                assert_eq!(value.signum(), expected, "move {} for {} on\n{}", action, player, board);
+            }
+        }
+    }
+
+    fn square_and_rectangular() -> [Board; 2] {
+        [board(&["XO-", "-X-", "O--"]), board(&["X-O-", "-XO-", "O--X"])]
+    }
+
+    #[test]
+    fn transforming_inputs_matches_transforming_boards() {
+        for board in square_and_rectangular() {
+            for symmetry in Symmetry::ALL {
+                for player in [Player::X, Player::O] {
+                    assert_eq!(
+                        transform_input(&board_to_input(&board, player), symmetry, board.width(), board.height()),
+                        board_to_input(&symmetry.apply(&board), player),
+                        "{:?} on\n{}", symmetry, board
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn inverse_undoes_the_transform() {
+        for board in square_and_rectangular() {
+            let (width, height) = (board.width(), board.height());
+            for symmetry in Symmetry::ALL {
+                assert_eq!(symmetry.inverse().apply(&symmetry.apply(&board)), board, "{:?}", symmetry);
+                let (new_width, new_height) = if symmetry.swaps_dimensions() { (height, width) } else { (width, height) };
+                for action in 0..board.size() {
+                    let mapped = symmetry.map_action(action, width, height);
+                    assert!(mapped < board.size());
+                    assert_eq!(symmetry.inverse().map_action(mapped, new_width, new_height), action, "{:?}", symmetry);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn equivalent_boards_share_the_canonical_board() {
+        for board in square_and_rectangular() {
+            let (canonical, symmetry) = canonical_board(&board);
+            assert_eq!(symmetry.apply(&board), canonical);
+            // symmetries that swap width and height change the shape, the canonical board keeps it
+            for other in Symmetry::of(board.width(), board.height()) {
+                assert_eq!(canonical_board(&other.apply(&board)).0, canonical, "{:?}", other);
+            }
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use serde::{Serialize, Deserialize};
+use rand::Rng;
+
+// How the weights of a new layer are drawn, biases always start at zero
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub enum Initializer {
+    // Glorot, suited to tanh and sigmoid layers
+    #[default]
+    XavierUniform,
+    XavierNormal,
+    // Kaiming, suited to the ReLU family
+    HeUniform,
+    HeNormal,
+    // Orthonormal rows (or columns, whichever are fewer)
+    Orthogonal,
+    Zeros,
+    Constant(f32),
+}
+
+impl Initializer {
+    // Row-major output_size x input_size weights, laid out like Layer::weights
+    pub fn weights(&self, input_size: usize, output_size: usize, rng: &mut impl Rng) -> Vec<f32> {
+        let count = input_size * output_size;
+        let fan_avg = (input_size + output_size) as f32 / 2.0;
+        let fan_in = input_size as f32;
+        match self {
+            Initializer::XavierUniform => uniform(count, (3.0 / fan_avg).sqrt(), rng),
+            Initializer::XavierNormal => normal(count, (1.0 / fan_avg).sqrt(), rng),
+            Initializer::HeUniform => uniform(count, (6.0 / fan_in).sqrt(), rng),
+            Initializer::HeNormal => normal(count, (2.0 / fan_in).sqrt(), rng),
+            Initializer::Orthogonal => orthogonal(input_size, output_size, rng),
+            Initializer::Zeros => vec![0.0; count],
+            Initializer::Constant(value) => vec![*value; count],
+        }
+    }
+}
+
+fn uniform(count: usize, limit: f32, rng: &mut impl Rng) -> Vec<f32> {
+    (0..count).map(|_| rng.gen_range(-limit..limit)).collect()
+}
+
+fn normal(count: usize, std_dev: f32, rng: &mut impl Rng) -> Vec<f32> {
+    (0..count).map(|_| standard_normal(rng) * std_dev).collect()
+}
+
+// Box-Muller transform, rand itself only ships uniform distributions
+fn standard_normal(rng: &mut impl Rng) -> f32 {
+    let u1: f32 = rng.gen_range(f32::EPSILON..1.0);
+    let u2: f32 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f32::consts::PI * u2).cos()
+}
+
+// Gram-Schmidt on a gaussian matrix. The shorter side gets orthonormal vectors: the rows when
+// there are no more outputs than inputs, the columns otherwise.
+fn orthogonal(input_size: usize, output_size: usize, rng: &mut impl Rng) -> Vec<f32> {
+    let (vectors, length) = if output_size <= input_size { (output_size, input_size) } else { (input_size, output_size) };
+    let mut basis: Vec<Vec<f32>> = Vec::with_capacity(vectors);
+    while basis.len() < vectors {
+        let mut v = normal(length, 1.0, rng);
+        for b in &basis {
+            let dot: f32 = v.iter().zip(b.iter()).map(|(x, y)| x * y).sum();
+            v.iter_mut().zip(b.iter()).for_each(|(x, y)| *x -= dot * y);
+        }
+        let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+        // a nearly dependent draw would lose precision, draw again
+        if norm > 1e-3 {
+            v.iter_mut().for_each(|x| *x /= norm);
+            basis.push(v);
+        }
+    }
+    if output_size <= input_size {
+        basis.concat()
+    } else {
+        (0..output_size).flat_map(|o| basis.iter().map(move |column| column[o])).collect()
+    }
+}
@@ -1,79 +1,320 @@
 use crate::activation::Activation;
+use crate::initializer::Initializer;
+use crate::optimizer::Optimizer;
+use crate::regularization::Regularizer;
 use serde::{Serialize, Deserialize};
-use rand::{thread_rng, Rng};
+use rand::Rng;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(try_from = "LayerRepr")]
 pub struct Layer {
-    pub weights: Vec<Vec<f32>>,
+    pub input_size: usize,
+    pub output_size: usize,
+    // Row-major output_size x input_size: the weights feeding output neuron o are
+    // weights[o * input_size .. (o + 1) * input_size]
+    pub weights: Vec<f32>,
     pub biases: Vec<f32>,
     pub activation: Activation,
+    // Learned PReLU slopes, one shared by the whole layer or one per neuron. The value carried by
+    // Activation::ParametricReLU is only the initial slope. Empty for every other activation.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alphas: Vec<f32>,
+    pub config: LayerConfig,
+}
+
+// Training settings of a single layer
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct LayerConfig {
+    pub regularizer: Regularizer,
+    pub regularize_biases: bool,
+    pub initializer: Initializer,
+}
+
+impl Default for LayerConfig {
+    fn default() -> Self {
+        Self { regularizer: Regularizer::default(), regularize_biases: true, initializer: Initializer::default() }
+    }
+}
+
+// What a saved layer may look like on disk. Networks saved before the flat layout stored the
+// weights nested and input-major (weights[input][output]) without explicit sizes.
+#[derive(Deserialize)]
+struct LayerRepr {
+    weights: WeightsRepr,
+    biases: Vec<f32>,
+    activation: Activation,
+    #[serde(default)]
+    alphas: Vec<f32>,
+    #[serde(default)]
+    config: LayerConfig,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum WeightsRepr {
+    Flat(Vec<f32>),
+    Nested(Vec<Vec<f32>>),
+}
+
+impl TryFrom<LayerRepr> for Layer {
+    type Error = &'static str;
+
+    fn try_from(repr: LayerRepr) -> Result<Self, Self::Error> {
+        let output_size = repr.biases.len();
+        let (input_size, weights) = match repr.weights {
+            WeightsRepr::Flat(weights) => (weights.len() / output_size.max(1), weights),
+            WeightsRepr::Nested(nested) => {
+                if nested.iter().any(|row| row.len() != output_size) {
+                    return Err("Every row of nested weights needs one weight per bias");
+                }
+                let input_size = nested.len();
+                let weights = (0..output_size)
+                    .flat_map(|o| nested.iter().map(move |row| row[o]))
+                    .collect();
+                (input_size, weights)
+            }
+        };
+        if weights.len() != input_size * output_size {
+            return Err("Number of weights is not a multiple of the number of biases");
+        }
+        let alphas = match (repr.activation, repr.alphas.is_empty()) {
+            // saved before the slopes were learned
+            (Activation::ParametricReLU(alpha), true) => vec![alpha],
+            _ => repr.alphas,
+        };
+        if alphas.len() > 1 && alphas.len() != output_size {
+            return Err("PReLU needs one slope for the layer or one per neuron");
+        }
+        Ok(Self { input_size, output_size, weights, biases: repr.biases, activation: repr.activation, alphas, config: repr.config })
+    }
+}
+
+// Everything a layer computed during one forward pass, kept so the backward pass
+// does not have to run forward again. For a batch the values of each sample follow each other,
+// backward only takes the trace of a single sample.
+#[derive(Clone, Debug)]
+pub struct LayerTrace {
+    pub pre_activations: Vec<f32>,
+    pub outputs: Vec<f32>,
+}
+
+// Loss gradients for one layer, shaped like the parameters they belong to
+#[derive(Clone, Debug)]
+pub struct LayerGradients {
+    pub weights: Vec<f32>,
+    pub biases: Vec<f32>,
+    pub alphas: Vec<f32>,
+}
+
+impl LayerGradients {
+    pub fn iter(&self) -> impl Iterator<Item = &f32> {
+        self.weights.iter().chain(self.biases.iter()).chain(self.alphas.iter())
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut f32> {
+        self.weights.iter_mut().chain(self.biases.iter_mut()).chain(self.alphas.iter_mut())
+    }
+
+    pub fn add(&mut self, other: &LayerGradients) {
+        self.iter_mut().zip(other.iter()).for_each(|(a, b)| *a += b);
+    }
 }
 
 impl Layer
 {
-    pub fn new(input_size: usize, output_size: usize, activation: Activation) -> Self {
-        let mut rng = thread_rng();
-        let weights = (0..input_size)
-            .map(|_| (0..output_size).map(|_| rng.gen_range(-((6.0 / (input_size + output_size) as f32).sqrt()) .. (6.0 / (input_size + output_size) as f32).sqrt())).collect())
-            .collect();
-        let biases = (0..output_size).map(|_| rng.gen_range(-((6.0 / (input_size + output_size) as f32).sqrt()) .. (6.0 / (input_size + output_size) as f32).sqrt())).collect();
-        Self { weights, biases, activation }
+    pub fn new(input_size: usize, output_size: usize, activation: Activation, config: LayerConfig, rng: &mut impl Rng) -> Self {
+        let weights = config.initializer.weights(input_size, output_size, rng);
+        let biases = vec![0.0; output_size];
+        let alphas = match activation {
+            Activation::ParametricReLU(alpha) => vec![alpha],
+            _ => Vec::new(),
+        };
+        Self { input_size, output_size, weights, biases, activation, alphas, config }
+    }
+
+    // Gives every neuron of a PReLU layer its own slope, starting from the current shared one
+    pub fn with_per_neuron_alphas(mut self) -> Self {
+        if let Activation::ParametricReLU(initial) = self.activation {
+            let alpha = self.alphas.first().copied().unwrap_or(initial);
+            self.alphas = vec![alpha; self.output_size];
+        }
+        self
+    }
+
+    // All trainable parameters, in the same order as LayerGradients::iter
+    pub fn parameters(&self) -> impl Iterator<Item = &f32> {
+        self.weights.iter().chain(self.biases.iter()).chain(self.alphas.iter())
+    }
+
+    pub fn parameters_mut(&mut self) -> impl Iterator<Item = &mut f32> {
+        self.weights.iter_mut().chain(self.biases.iter_mut()).chain(self.alphas.iter_mut())
+    }
+
+    // The activation applied to one output neuron, with the learned slope for PReLU layers
+    fn neuron_activation(&self, neuron_index: usize) -> Activation {
+        match self.activation {
+            Activation::ParametricReLU(initial) => {
+                let alpha = match self.alphas.len() {
+                    0 => initial,
+                    1 => self.alphas[0],
+                    _ => self.alphas[neuron_index],
+                };
+                Activation::ParametricReLU(alpha)
+            }
+            activation => activation,
+        }
     }
 
     pub fn forward(&self, input: &[f32]) -> Vec<f32> {
-        let mut result = vec![0.0; self.biases.len()];
-        for (output_neuron_index, bias) in self.biases.iter().enumerate() {
-            let weight_sum = input.iter().enumerate()
-                .map(|(input_neuron_index, input_value)| {
-                    self.weights[input_neuron_index][output_neuron_index] * input_value
-                })
-                .sum::<f32>();
-
-            result[output_neuron_index] = self.activation.compute(weight_sum + bias);
+        self.forward_trace(input).outputs
+    }
+
+    pub fn forward_trace(&self, input: &[f32]) -> LayerTrace {
+        self.forward_batch_trace(input, 1)
+    }
+
+    // Forward pass for `batch` samples laid out one after another in `input`,
+    // returns batch x output_size outputs
+    pub fn forward_batch(&self, input: &[f32], batch: usize) -> Vec<f32> {
+        self.forward_batch_trace(input, batch).outputs
+    }
+
+    pub fn forward_batch_trace(&self, input: &[f32], batch: usize) -> LayerTrace {
+        assert_eq!(input.len(), batch * self.input_size, "Input does not match the layer input size");
+        // batch x input times the transposed output x input weights, both walked row by row
+        let mut pre_activations = Vec::with_capacity(batch * self.output_size);
+        for sample in input.chunks_exact(self.input_size) {
+            for (neuron_weights, bias) in self.weights.chunks_exact(self.input_size).zip(self.biases.iter()) {
+                let weight_sum = sample.iter().zip(neuron_weights.iter()).map(|(x, w)| x * w).sum::<f32>();
+                pre_activations.push(weight_sum + bias);
+            }
         }
-        result
-    }
-    const L1_REGULARIZATION: f32 = 0.001;
-    const L2_REGULARIZATION: f32 = 0.001;
-    pub fn update_weights_and_biases(&mut self, input: &[f32], errors: &[f32], learning_rate: f32) {
-        let forward_result = self.forward(input); // Call forward once and store the result
-        
-        let derivatives: Vec<f32> = forward_result
-        .iter()
-        .map(|output| self.activation.derivative(*output))
-        .collect();
-
-        let deltas: Vec<f32> = errors
-            .iter()
-            .zip(derivatives.iter())
-            .map(|(error, derivative)| error * derivative)
-            .collect();
-        
-        // Clip the deltas to avoid exploding gradients
-        let clip_threshold = 10.0;
-        let clipped_deltas: Vec<f32> = deltas
-            .iter()
-            .map(|delta| delta.min(clip_threshold).max(-clip_threshold))
+        let outputs = pre_activations.chunks_exact(self.output_size)
+            .flat_map(|sample| self.activate(sample))
             .collect();
+        LayerTrace { pre_activations, outputs }
+    }
 
-        for output_node_index in 0..clipped_deltas.len(){
-            let clipped_delta = clipped_deltas[output_node_index];
-            if clipped_delta.abs() < 1e-6 {
-                continue;
+    fn activate(&self, pre_activations: &[f32]) -> Vec<f32> {
+        match self.activation {
+            Activation::ParametricReLU(_) => pre_activations.iter().enumerate()
+                .map(|(neuron_index, x)| self.neuron_activation(neuron_index).compute(*x))
+                .collect(),
+            activation => activation.apply(pre_activations),
+        }
+    }
+
+    // Gradients of the loss with respect to this layer's parameters and input, given the gradient
+    // with respect to its outputs. Only reads the weights, so it always matches the forward pass
+    // that produced the trace. Works on one sample, sum the gradients of a batch.
+    pub fn backward(&self, input: &[f32], trace: &LayerTrace, output_gradient: &[f32]) -> (LayerGradients, Vec<f32>) {
+        assert_eq!(input.len(), self.input_size, "Backward takes a single sample");
+        assert_eq!(output_gradient.len(), self.output_size, "Output gradient does not match the layer output size");
+        let (deltas, alphas) = match self.activation {
+            Activation::ParametricReLU(_) => {
+                let deltas = output_gradient.iter().zip(trace.pre_activations.iter()).enumerate()
+                    .map(|(neuron_index, (g, x))| g * self.neuron_activation(neuron_index).derivative(*x))
+                    .collect();
+                // d output / d alpha is x on the negative side, summed over the neurons sharing the slope
+                let mut alphas = vec![0.0; self.alphas.len()];
+                let shared = alphas.len() == 1;
+                for (neuron_index, (g, x)) in output_gradient.iter().zip(trace.pre_activations.iter()).enumerate() {
+                    if *x <= 0.0 {
+                        if let Some(alpha) = alphas.get_mut(if shared { 0 } else { neuron_index }) {
+                            *alpha += g * x;
+                        }
+                    }
+                }
+                (deltas, alphas)
             }
-            
-            for (input_node_index, input_value) in input.iter().enumerate() {
-                let w = &mut self.weights[input_node_index][output_node_index];
-                let l1_regularization = Self::L1_REGULARIZATION * w.signum();
-                let l2_regularization = Self::L2_REGULARIZATION * *w;
-    
-                *w += learning_rate * (clipped_delta * input_value - (l1_regularization + l2_regularization));
+            activation => (activation.backward(&trace.pre_activations, &trace.outputs, output_gradient), Vec::new()),
+        };
+
+        let weights = deltas.iter()
+            .flat_map(|delta| input.iter().map(move |input_value| delta * input_value))
+            .collect();
+        let mut input_gradient = vec![0.0; self.input_size];
+        for (neuron_weights, delta) in self.weights.chunks_exact(self.input_size).zip(deltas.iter()) {
+            for (g, w) in input_gradient.iter_mut().zip(neuron_weights.iter()) {
+                *g += w * delta;
             }
-            let learn_delta = clipped_delta * learning_rate;
-            let bias = self.biases[output_node_index];
-            let l1_regularization = Self::L1_REGULARIZATION * bias.signum();
-            let l2_regularization = Self::L2_REGULARIZATION * bias;
-            self.biases[output_node_index] += learn_delta - learning_rate * (l1_regularization + l2_regularization);
         }
+
+        (LayerGradients { weights, biases: deltas, alphas }, input_gradient)
     }
-}
\ No newline at end of file
+
+    // Parameter groups a layer hands to the optimizer: weights, biases and PReLU slopes
+    pub const PARAMETER_GROUPS: usize = 3;
+
+    // Adds the regularization of this layer to the (already clipped) gradients and lets the
+    // optimizer update the parameters. The groups of this layer use the optimizer slots starting
+    // at first_slot.
+    pub fn apply_gradients(&mut self, gradients: &LayerGradients, first_slot: usize, optimizer: &mut impl Optimizer) {
+        let regularizer = self.config.regularizer;
+        let regularized = |params: &[f32], gradients: &[f32]| -> Vec<f32> {
+            params.iter().zip(gradients.iter()).map(|(p, g)| g + regularizer.gradient(*p)).collect()
+        };
+
+        let weight_gradients = regularized(&self.weights, &gradients.weights);
+        optimizer.update(first_slot, &mut self.weights, &weight_gradients);
+        if self.config.regularize_biases {
+            let bias_gradients = regularized(&self.biases, &gradients.biases);
+            optimizer.update(first_slot + 1, &mut self.biases, &bias_gradients);
+        } else {
+            optimizer.update(first_slot + 1, &mut self.biases, &gradients.biases);
+        }
+        // The slopes are not regularized, a decay would pull PReLU back towards ReLU
+        if !self.alphas.is_empty() {
+            optimizer.update(first_slot + 2, &mut self.alphas, &gradients.alphas);
+        }
+
+        let decay = 1.0 - optimizer.learning_rate() * regularizer.decay();
+        if decay != 1.0 {
+            self.weights.iter_mut().for_each(|w| *w *= decay);
+            if self.config.regularize_biases {
+                self.biases.iter_mut().for_each(|b| *b *= decay);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::network::NeuralNetwork;
+
+    // Saved before the flat layout: weights[input][output], no sizes
+    const NESTED_NETWORK: &str = r#"{"layers": [
+        {"weights": [[1.0, 2.0], [3.0, 4.0], [5.0, 6.0]], "biases": [0.5, -0.5], "activation": "Identity"},
+        {"weights": [[1.0], [-1.0]], "biases": [0.25], "activation": "Identity"}
+    ]}"#;
+
+    #[test]
+    fn loads_nested_input_major_weights() {
+        let network: NeuralNetwork = serde_json::from_str(NESTED_NETWORK).unwrap();
+        let first = &network.layers[0];
+        assert_eq!((first.input_size, first.output_size), (3, 2));
+        assert_eq!(first.weights, vec![1.0, 3.0, 5.0, 2.0, 4.0, 6.0]);
+
+        // hidden: 1 + 0 - 5 + 0.5 = -3.5 and 2 + 0 - 6 - 0.5 = -4.5, output: -3.5 + 4.5 + 0.25
+        assert_eq!(network.forward(&[1.0, 0.0, -1.0]), vec![1.25]);
+    }
+
+    #[test]
+    fn rejects_misshaped_weights() {
+        let ragged = r#"{"layers": [{"weights": [[1.0, 2.0], [3.0]], "biases": [0.0, 0.0], "activation": "Identity"}]}"#;
+        assert!(serde_json::from_str::<NeuralNetwork>(ragged).is_err());
+        let flat = r#"{"layers": [{"weights": [1.0, 2.0, 3.0], "biases": [0.0, 0.0], "activation": "Identity"}]}"#;
+        assert!(serde_json::from_str::<NeuralNetwork>(flat).is_err());
+        let alphas = r#"{"layers": [{"weights": [1.0, 2.0, 3.0], "biases": [0.0, 0.0, 0.0], "activation": {"ParametricReLU": 0.25}, "alphas": [0.1, 0.2]}]}"#;
+        assert!(serde_json::from_str::<NeuralNetwork>(alphas).is_err());
+    }
+
+    #[test]
+    fn nested_weights_survive_a_save() {
+        let network: NeuralNetwork = serde_json::from_str(NESTED_NETWORK).unwrap();
+        let reloaded: NeuralNetwork = serde_json::from_str(&serde_json::to_string(&network).unwrap()).unwrap();
+        let input = [0.5, -2.0, 1.5];
+        assert_eq!(reloaded.forward(&input), network.forward(&input));
+    }
+}
@@ -0,0 +1,15 @@
+pub mod game;
+pub mod network;
+pub mod train;
+pub mod layer;
+pub mod activation;
+pub mod regularization;
+pub mod initializer;
+pub mod optimizer;
+pub mod loss;
+pub mod replay;
+pub mod config;
+pub mod eval;
+pub mod pretrain;
+pub mod connect_four;
+pub mod ultimate;
@@ -0,0 +1,225 @@
+use serde::{Serialize, Deserialize};
+
+// Scalar training loss between the network output and a target, plus its gradient with respect
+// to the output. The element-wise losses average over the outputs.
+pub trait Loss: Send + Sync {
+    fn loss(&self, output: &[f32], target: &[f32]) -> f32;
+
+    fn gradient(&self, output: &[f32], target: &[f32]) -> Vec<f32>;
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Mse;
+
+impl Loss for Mse {
+    fn loss(&self, output: &[f32], target: &[f32]) -> f32 {
+        output.iter().zip(target.iter()).map(|(o, t)| (o - t).powi(2)).sum::<f32>() / output.len() as f32
+    }
+
+    fn gradient(&self, output: &[f32], target: &[f32]) -> Vec<f32> {
+        let n = output.len() as f32;
+        output.iter().zip(target.iter()).map(|(o, t)| 2.0 * (o - t) / n).collect()
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Mae;
+
+impl Loss for Mae {
+    fn loss(&self, output: &[f32], target: &[f32]) -> f32 {
+        output.iter().zip(target.iter()).map(|(o, t)| (o - t).abs()).sum::<f32>() / output.len() as f32
+    }
+
+    fn gradient(&self, output: &[f32], target: &[f32]) -> Vec<f32> {
+        let n = output.len() as f32;
+        output.iter().zip(target.iter())
+            .map(|(o, t)| if o == t { 0.0 } else { (o - t).signum() / n })
+            .collect()
+    }
+}
+
+// Squared error for errors up to delta and linear beyond, so a few huge targets
+// (like the illegal move penalty) do not dominate the update
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Huber {
+    pub delta: f32,
+}
+
+impl Huber {
+    pub fn new(delta: f32) -> Self {
+        Self { delta }
+    }
+}
+
+impl Loss for Huber {
+    fn loss(&self, output: &[f32], target: &[f32]) -> f32 {
+        output.iter().zip(target.iter())
+            .map(|(o, t)| {
+                let error = (o - t).abs();
+                if error <= self.delta { 0.5 * error * error } else { self.delta * (error - 0.5 * self.delta) }
+            })
+            .sum::<f32>() / output.len() as f32
+    }
+
+    fn gradient(&self, output: &[f32], target: &[f32]) -> Vec<f32> {
+        let n = output.len() as f32;
+        output.iter().zip(target.iter()).map(|(o, t)| (o - t).clamp(-self.delta, self.delta) / n).collect()
+    }
+}
+
+// For sigmoid outputs, the output is read as the probability of each target being 1
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct BinaryCrossEntropy;
+
+const PROBABILITY_EPSILON: f32 = 1e-7;
+
+impl Loss for BinaryCrossEntropy {
+    fn loss(&self, output: &[f32], target: &[f32]) -> f32 {
+        output.iter().zip(target.iter())
+            .map(|(o, t)| {
+                let p = o.clamp(PROBABILITY_EPSILON, 1.0 - PROBABILITY_EPSILON);
+                -(t * p.ln() + (1.0 - t) * (1.0 - p).ln())
+            })
+            .sum::<f32>() / output.len() as f32
+    }
+
+    fn gradient(&self, output: &[f32], target: &[f32]) -> Vec<f32> {
+        let n = output.len() as f32;
+        output.iter().zip(target.iter())
+            .map(|(o, t)| {
+                let p = o.clamp(PROBABILITY_EPSILON, 1.0 - PROBABILITY_EPSILON);
+                (p - t) / (p * (1.0 - p)) / n
+            })
+            .collect()
+    }
+}
+
+// Cross-entropy of a target distribution against the softmax of the output. Expects raw logits,
+// so the output layer should use Activation::Identity rather than Softmax.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct SoftmaxCrossEntropy;
+
+impl SoftmaxCrossEntropy {
+    fn log_softmax(logits: &[f32]) -> Vec<f32> {
+        let max = logits.iter().fold(f32::NEG_INFINITY, |acc, x| acc.max(*x));
+        let log_sum = logits.iter().map(|x| (x - max).exp()).sum::<f32>().ln() + max;
+        logits.iter().map(|x| x - log_sum).collect()
+    }
+}
+
+impl Loss for SoftmaxCrossEntropy {
+    fn loss(&self, output: &[f32], target: &[f32]) -> f32 {
+        -Self::log_softmax(output).iter().zip(target.iter()).map(|(l, t)| t * l).sum::<f32>()
+    }
+
+    fn gradient(&self, output: &[f32], target: &[f32]) -> Vec<f32> {
+        let target_sum: f32 = target.iter().sum();
+        Self::log_softmax(output).iter().zip(target.iter()).map(|(l, t)| l.exp() * target_sum - t).collect()
+    }
+}
+
+// Any of the losses above, chosen at runtime (e.g. from a training config)
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum LossFunction {
+    Mse,
+    Mae,
+    Huber { delta: f32 },
+    BinaryCrossEntropy,
+    SoftmaxCrossEntropy,
+}
+
+impl Loss for LossFunction {
+    fn loss(&self, output: &[f32], target: &[f32]) -> f32 {
+        match *self {
+            LossFunction::Mse => Mse.loss(output, target),
+            LossFunction::Mae => Mae.loss(output, target),
+            LossFunction::Huber { delta } => Huber::new(delta).loss(output, target),
+            LossFunction::BinaryCrossEntropy => BinaryCrossEntropy.loss(output, target),
+            LossFunction::SoftmaxCrossEntropy => SoftmaxCrossEntropy.loss(output, target),
+        }
+    }
+
+    fn gradient(&self, output: &[f32], target: &[f32]) -> Vec<f32> {
+        match *self {
+            LossFunction::Mse => Mse.gradient(output, target),
+            LossFunction::Mae => Mae.gradient(output, target),
+            LossFunction::Huber { delta } => Huber::new(delta).gradient(output, target),
+            LossFunction::BinaryCrossEntropy => BinaryCrossEntropy.gradient(output, target),
+            LossFunction::SoftmaxCrossEntropy => SoftmaxCrossEntropy.gradient(output, target),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOSSES: [LossFunction; 6] = [
+        LossFunction::Mse,
+        LossFunction::Mae,
+        LossFunction::Huber { delta: 1.0 },
+        LossFunction::Huber { delta: 0.3 },
+        LossFunction::BinaryCrossEntropy,
+        LossFunction::SoftmaxCrossEntropy,
+    ];
+
+    // Errors output - target where the gradient jumps, finite differences are meaningless there
+    fn kinks(loss: &LossFunction) -> Vec<f32> {
+        match *loss {
+            LossFunction::Mae => vec![0.0],
+            LossFunction::Huber { delta } => vec![-delta, delta],
+            _ => Vec::new(),
+        }
+    }
+
+    // Outputs and targets in the range the loss is meant for: probabilities for binary
+    // cross-entropy, a distribution that does not sum to 1 for softmax cross-entropy
+    fn cases(loss: &LossFunction) -> Vec<(Vec<f32>, Vec<f32>)> {
+        (0..20)
+            .map(|i| {
+                let i = i as f32;
+                match loss {
+                    LossFunction::BinaryCrossEntropy => (
+                        (0..4).map(|j| 0.05 + 0.9 * ((i * 0.37 + j as f32 * 0.23) % 1.0)).collect(),
+                        vec![0.0, 1.0, 0.3, 0.8],
+                    ),
+                    LossFunction::SoftmaxCrossEntropy => (
+                        (0..4).map(|j| (i * 0.7 + j as f32 * 1.3).sin() * 2.0).collect(),
+                        vec![0.2, 0.5, 0.1, 0.0],
+                    ),
+                    _ => (
+                        (0..4).map(|j| (i * 0.9 + j as f32 * 1.7).sin() * 2.0).collect(),
+                        (0..4).map(|j| (i * 0.4 + j as f32 * 0.8).cos() * 1.5).collect(),
+                    ),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn gradient_matches_finite_differences() {
+        let h = 1e-3;
+        for loss in LOSSES {
+            for (output, target) in cases(&loss) {
+                let gradient = loss.gradient(&output, &target);
+                for index in 0..output.len() {
+                    let error = output[index] - target[index];
+                    if kinks(&loss).iter().any(|k| (error - k).abs() < 2.0 * h) {
+                        continue;
+                    }
+                    let shifted = |delta: f32| {
+                        let mut output = output.clone();
+                        output[index] += delta;
+                        loss.loss(&output, &target)
+                    };
+                    let numeric = (shifted(h) - shifted(-h)) / (2.0 * h);
+                    let analytic = gradient[index];
+                    assert!(
+                        (numeric - analytic).abs() < 1e-2 * analytic.abs().max(1.0),
+                        "{:?} at {:?} -> {:?}, output {}: finite difference {} vs gradient {}", loss, output, target, index, numeric, analytic
+                    );
+                }
+            }
+        }
+    }
+}
@@ -1,19 +1,238 @@
-use activation::Activation;
-use game::{Board, BOARD_SIZE, empty_board, check_winner, make_move, play_random_move, is_full};
-use train::{board_to_input, save_network, load_network};
-use network::NeuralNetwork;
+use rustic::activation::Activation;
+use rustic::config::{GameKind, TrainConfig};
+use rustic::connect_four::ConnectFour;
+use rustic::game::{Bits, Board, Game, Outcome, Player, Wide};
+use rustic::ultimate::UltimateTicTacToe;
+use rustic::eval::{self, AlphaBetaOpponent, HeuristicOpponent, MinimaxOpponent, NetworkOpponent, Opponent, RandomOpponent, Seat};
+use rustic::train::{self, config_rng, save_network, load_network, save_optimizer, load_optimizer};
+use rustic::initializer::Initializer;
+use rustic::layer::LayerConfig;
+use rustic::regularization::Regularizer;
+use rustic::network::NeuralNetwork;
+use rustic::optimizer::AnyOptimizer;
+use rustic::pretrain;
 
-mod game;
-mod network;
-mod train;
-mod layer;
-mod activation;
+const MAX_SOLVED_CELLS: usize = 16;
+// Connect Four opponent while training, deep enough to punish loose play and still quick
+const ALPHA_BETA_DEPTH: usize = 4;
 
+// Usage:
+//   rustic [--config train_config.json] [--<config key> <value>]...
+//       train until the network goes 100 games without losing against perfect play (alpha-beta
+//       search for Connect Four), e.g. rustic --episodes 20000 --optimizer.Adam.learning_rate 0.0005
+//       or rustic --game ConnectFour, ultimate tic-tac-toe (--game UltimateTicTacToe) trains
+//       against random play
+//   rustic eval <random|other_network.json|game opponent> [games] [x|o|alternate]
+//       play the trained network against an opponent and print the report as JSON. m,n,k boards
+//       know heuristic and minimax, Connect Four alphabeta[:depth], ultimate tic-tac-toe only random.
+//   rustic accuracy [blunders to list]          (m,n,k boards up to MAX_SOLVED_CELLS cells)
+//       compare the network's greedy move with the minimax-optimal moves in every reachable position
+//   rustic pretrain                             (m,n,k boards up to MAX_SOLVED_CELLS cells)
+//       fit a fresh network to minimax labels (see --pretrain.* keys) and save it as the starting
+//       point for training
+// Every game and board size keeps its own files, see network_path and optimizer_state_path.
 fn main() {
-    let trained_network_path = "trained_network.json";
-    let mut network = match load_network(trained_network_path, &[18, 16, 14, 9], &[Activation::Tanh, Activation::Tanh, Activation::Tanh]) {
-        Ok(model) => model,
-        Err(_) => NeuralNetwork::new(&[18, 16, 14, 9], &[Activation::Tanh, Activation::Tanh, Activation::Tanh]),
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (config, rest) = match TrainConfig::from_args(&args) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            println!("Invalid configuration: {}", e);
+            return
+        }
+    };
+    if let (GameKind::Board, Err(e)) = (config.game, config.board.validate()) {
+        println!("Invalid configuration: {}", e);
+        return
+    }
+    match config.game {
+        // The smallest mask type that holds every cell of the board
+        GameKind::Board => match config.board.cells() {
+            cells if cells <= <u16 as Bits>::BITS => run_board::<u16>(&config, &rest),
+            cells if cells <= <u32 as Bits>::BITS => run_board::<u32>(&config, &rest),
+            cells if cells <= <u64 as Bits>::BITS => run_board::<u64>(&config, &rest),
+            cells if cells <= <u128 as Bits>::BITS => run_board::<u128>(&config, &rest),
+            cells if cells <= Wide::BITS => run_board::<Wide>(&config, &rest),
+            cells => println!("Boards have at most {} cells, this one has {}", Wide::BITS, cells),
+        },
+        GameKind::ConnectFour => {
+            run_game(&config, &rest, &ConnectFour::new(), &mut AlphaBetaOpponent { depth: ALPHA_BETA_DEPTH }, connect_four_opponent)
+        }
+        GameKind::UltimateTicTacToe => run_game(&config, &rest, &UltimateTicTacToe::new(), &mut RandomOpponent, |_, _| Ok(None)),
+    }
+}
+
+// The m,n,k commands, with B wide enough for the configured board
+fn run_board<B: Bits>(config: &TrainConfig, rest: &[String]) {
+    let board: Board<B> = config.board.empty_board();
+    match rest.first().map(String::as_str) {
+        Some("accuracy") => {
+            if let Err(e) = run_accuracy(&rest[1..], config, &board) {
+                println!("Accuracy check failed: {}", e);
+            }
+        }
+        Some("pretrain") => {
+            if let Err(e) = run_pretrain(config, &board) {
+                println!("Pretraining failed: {}", e);
+            }
+        }
+        _ => {
+            // The solver cannot search boards much bigger than 3x3, there the heuristic stands in
+            let mut opponent: Box<dyn Opponent<Board<B>>> = if board.size() <= MAX_SOLVED_CELLS {
+                Box::new(MinimaxOpponent::default())
+            } else {
+                Box::new(HeuristicOpponent)
+            };
+            run_game(config, rest, &board, opponent.as_mut(), board_opponent::<B>)
+        }
+    }
+}
+
+// Training against training_opponent, or evaluation against named or random opponents
+fn run_game<G: Game>(config: &TrainConfig, rest: &[String], game: &G, training_opponent: &mut dyn Opponent<G>, named_opponent: NamedOpponent<G>) {
+    match rest.first().map(String::as_str) {
+        None => train_until_no_loss(config, game, training_opponent),
+        Some("eval") => {
+            if let Err(e) = run_eval(&rest[1..], config, game, named_opponent) {
+                println!("Evaluation failed: {}", e);
+            }
+        }
+        Some(command @ ("accuracy" | "pretrain")) => {
+            println!("{} needs the minimax solver and only works on m,n,k boards", command);
+        }
+        Some(_) => println!("Unexpected arguments: {:?}", rest),
+    }
+}
+
+// Looks up an opponent the game knows by name, Ok(None) for names it does not know
+type NamedOpponent<G> = fn(&str, &G) -> OpponentLookup<G>;
+type OpponentLookup<G> = Result<Option<Box<dyn Opponent<G>>>, Box<dyn std::error::Error>>;
+
+// The built-in opponents of m,n,k games besides random play
+fn board_opponent<B: Bits>(name: &str, board: &Board<B>) -> OpponentLookup<Board<B>> {
+    Ok(match name {
+        "heuristic" => Some(Box::new(HeuristicOpponent)),
+        // the solver searches the whole game tree, which never finishes on bigger boards
+        "minimax" if board.size() > MAX_SOLVED_CELLS => {
+            return Err(format!("minimax only handles boards up to {} cells", MAX_SOLVED_CELLS).into());
+        }
+        "minimax" => Some(Box::new(MinimaxOpponent::default())),
+        _ => None,
+    })
+}
+
+// alphabeta searches ALPHA_BETA_DEPTH plies, alphabeta:<depth> as deep as given
+fn connect_four_opponent(name: &str, _game: &ConnectFour) -> OpponentLookup<ConnectFour> {
+    let depth = match name.strip_prefix("alphabeta") {
+        Some("") => Some(ALPHA_BETA_DEPTH),
+        Some(depth) => depth.strip_prefix(':').and_then(|depth| depth.parse().ok()),
+        None => None,
+    };
+    Ok(depth.map(|depth| Box::new(AlphaBetaOpponent { depth }) as Box<dyn Opponent<ConnectFour>>))
+}
+
+// Names the files of the configured game, e.g. 3x3k3 or connect_four
+fn model_name(config: &TrainConfig) -> String {
+    match config.game {
+        GameKind::Board => format!("{}x{}k{}", config.board.width, config.board.height, config.board.win_length),
+        GameKind::ConnectFour => "connect_four".to_string(),
+        GameKind::UltimateTicTacToe => "ultimate".to_string(),
+    }
+}
+
+fn network_path(config: &TrainConfig) -> String {
+    format!("trained_network_{}.json", model_name(config))
+}
+
+fn optimizer_state_path(config: &TrainConfig) -> String {
+    format!("optimizer_state_{}.json", model_name(config))
+}
+
+// The opponent is "random", one the game knows by name, or else the path of a saved network
+fn run_eval<G: Game>(args: &[String], config: &TrainConfig, game: &G, named_opponent: NamedOpponent<G>) -> Result<(), Box<dyn std::error::Error>> {
+    let network = load_network(&network_path(config), &layer_sizes(game), &ACTIVATIONS)?;
+    let mut opponent: Box<dyn Opponent<G>> = match args.first().map(String::as_str).unwrap_or("random") {
+        "random" => Box::new(RandomOpponent),
+        name => match named_opponent(name, game)? {
+            Some(opponent) => opponent,
+            None => Box::new(NetworkOpponent(load_network(name, &layer_sizes(game), &ACTIVATIONS)?)),
+        },
+    };
+    let games = match args.get(1) {
+        Some(games) => games.parse()?,
+        None => 1000,
+    };
+    let seat = match args.get(2).map(String::as_str).unwrap_or("alternate") {
+        "x" => Seat::X,
+        "o" => Seat::O,
+        "alternate" => Seat::Alternate,
+        other => return Err(format!("Unknown seat: {}", other).into()),
+    };
+
+    let report = eval::evaluate(&network, opponent.as_mut(), game, games, seat, &mut config_rng(config));
+    println!("{}", report.to_json());
+    Ok(())
+}
+
+fn run_accuracy<B: Bits>(args: &[String], config: &TrainConfig, board: &Board<B>) -> Result<(), Box<dyn std::error::Error>> {
+    if board.size() > MAX_SOLVED_CELLS {
+        return Err(format!("the solver only handles boards up to {} cells", MAX_SOLVED_CELLS).into());
+    }
+    let network = load_network(&network_path(config), &layer_sizes(board), &ACTIVATIONS)?;
+    let listed = match args.first() {
+        Some(count) => count.parse()?,
+        None => 20,
+    };
+
+    let mut report = eval::move_accuracy(&network, board, &mut config_rng(config));
+    println!("{} of {} positions played optimally ({:.1}%), {} illegal moves, mean rank correlation {:.3}",
+        report.optimal, report.positions, report.accuracy * 100.0, report.illegal, report.mean_rank_correlation);
+    report.blunders.truncate(listed);
+    println!("{}", report.to_json());
+    Ok(())
+}
+
+const ACTIVATIONS: [Activation; 3] = [Activation::Tanh, Activation::Tanh, Activation::Identity];
+
+// One input per observation value and one Q-value per action, the hidden layers were sized for
+// 3x3 tic-tac-toe and grow with the observation
+fn layer_sizes(game: &impl Game) -> [usize; 4] {
+    let inputs = game.observation_size();
+    [inputs, 8 * inputs / 9, 7 * inputs / 9, game.action_space()]
+}
+
+fn new_network(config: &TrainConfig, game: &impl Game) -> NeuralNetwork {
+    let [input, first, second, output] = layer_sizes(game);
+    // No coupled L1/L2 penalty: with Adam it outweighs the small TD gradients and flattens the network
+    NeuralNetwork::builder(input)
+        .layer_config(LayerConfig { regularizer: Regularizer::None, initializer: Initializer::XavierUniform, ..LayerConfig::default() })
+        .layer(first, ACTIVATIONS[0])
+        .layer(second, ACTIVATIONS[1])
+        .layer(output, ACTIVATIONS[2])
+        .build(&mut config_rng(config))
+}
+
+fn run_pretrain<B: Bits>(config: &TrainConfig, board: &Board<B>) -> Result<(), Box<dyn std::error::Error>> {
+    if board.size() > MAX_SOLVED_CELLS {
+        return Err(format!("the solver only handles boards up to {} cells", MAX_SOLVED_CELLS).into());
+    }
+    let network = pretrain::pretrain(new_network(config, board), board, config);
+    let path = network_path(config);
+    save_network(&network, Some(config), &path)?;
+    // a stale optimizer state belongs to the network that was just replaced
+    let _ = std::fs::remove_file(optimizer_state_path(config));
+    println!("Pretrained network saved to {}", path);
+    Ok(())
+}
+
+// Trains until the network, playing X, goes a streak of games without losing to opponent.
+// Against perfect play that means the network holds the draw.
+fn train_until_no_loss<G: Game>(config: &TrainConfig, game: &G, opponent: &mut dyn Opponent<G>) {
+    let trained_network_path = &network_path(config);
+    let optimizer_state_path = &optimizer_state_path(config);
+    // The optimizer state only belongs to the saved network, a fresh network starts a fresh optimizer
+    let (mut network, mut optimizer): (NeuralNetwork, AnyOptimizer) = match load_network(trained_network_path, &layer_sizes(game), &ACTIVATIONS) {
+        Ok(model) => (model, load_optimizer(optimizer_state_path, &config.optimizer).unwrap_or_else(|_| config.optimizer.build())),
+        Err(_) => (new_network(config, game), config.optimizer.build()),
     };
 
     let mut no_loss_streak = -1;
@@ -22,7 +241,7 @@ fn main() {
         if no_loss_streak == 0 {
             println!("Loss: training network...");
             
-            network = match train::train(network) {
+            network = match train::train(network, game, config, &mut optimizer) {
                 Ok(n) => {
                     println!("Training complete.");
                     n
@@ -33,49 +252,47 @@ fn main() {
                 }
             };
             // Save the trained model
-            if let Err(e) = save_network(&network, trained_network_path) {
+            if let Err(e) = save_network(&network, Some(config), trained_network_path) {
                 println!("Error saving network: {:?}", e);
             }
+            if let Err(e) = save_optimizer(&optimizer, &config.optimizer, optimizer_state_path) {
+                println!("Error saving optimizer state: {:?}", e);
+            }
         }
         if no_loss_streak == -1 {no_loss_streak = 0;}
         
         // Simulate a game using the current network
-        let mut board = empty_board();
-        let mut player = 'X';
-        let mut boards = vec![];
+        let mut state_of_play = game.initial_state();
+        let mut states = vec![];
         let mut illegal_move = false;
-        while check_winner(&board).is_none() && !is_full(&board) {
-            if player == 'X' {
-                let state = board_to_input(&board, 'X');
+        while state_of_play.outcome().is_none() {
+            let player = state_of_play.current_player();
+            if player == Player::X {
+                let state = state_of_play.observation(player);
                 
-                let action = train::epsilon_greedy(&network, &state, 0.0, &board, false); // Use epsilon_greedy with epsilon set to 0.0 (exploit)
-
+                let action = train::epsilon_greedy(&network, &state, 0.0, &state_of_play, false, &mut rand::thread_rng()); // Use epsilon_greedy with epsilon set to 0.0 (exploit)
 
-                let (row, col) = (action / BOARD_SIZE, action % BOARD_SIZE);
-                match make_move(&mut board, player, row, col) {
-                    Ok(_) => (),
-                    Err(_) => {
-                        println!("Invalid move by 'X' on {col}x{row}. Game Over!");
-                        illegal_move = true;
-                        break;
-                    }
+                if state_of_play.apply(action).is_err() {
+                    println!("Invalid move by 'X': action {}. Game Over!", action);
+                    illegal_move = true;
+                    break;
                 }
             } else {
-                if let Err(_) = play_random_move(&mut board, player) {
+                let action = opponent.choose(&state_of_play, &mut rand::thread_rng());
+                if state_of_play.apply(action).is_err() {
                     println!("Invalid move by 'O'.");
                     continue;
                 }
             }
 
-            player = if player == 'X' { 'O' } else { 'X' };
-            boards.push(board.clone());
+            states.push(state_of_play.clone());
         }
 
-        print_boards_horizontally(&boards);
-        match check_winner(&board) {
-            Some(winner) => {
+        print_boards_horizontally(&states);
+        match state_of_play.outcome() {
+            Some(Outcome::Win(winner)) => {
                 print!("Winner: {}", winner);
-                if winner == 'O' {
+                if winner == Player::O {
                     println!(" No Loss streak broken at: {}/{}", no_loss_streak, no_loss_streak_limit);
                     no_loss_streak = 0;
                 }
@@ -84,7 +301,7 @@ fn main() {
                     println!(" {}/{}", no_loss_streak, no_loss_streak_limit);
                 }
             }
-            None => {
+            _ => {
                 if illegal_move {
                     println!("Illegal move. No Loss streak broken at: {}/{}", no_loss_streak, no_loss_streak_limit);
                     no_loss_streak = 0;
@@ -100,14 +317,20 @@ fn main() {
     }
 }
 
-fn print_boards_horizontally(boards: &[Board]) {
-    for row in 0..BOARD_SIZE {
-        for board in boards {
-            for col in 0..BOARD_SIZE {
-                print!("{} ", board[row][col]);
+// Prints the states side by side, wrapping onto more lines for wide boards or long games
+fn print_boards_horizontally(states: &[impl Game]) {
+    let rendered: Vec<Vec<String>> = states.iter().map(|state| state.to_string().lines().map(str::to_string).collect()).collect();
+    let width = rendered.iter().flatten().map(|line| line.chars().count()).max().unwrap_or(0);
+    let per_line = (120 / (width + 3)).max(1);
+    for line in rendered.chunks(per_line) {
+        let height = line.iter().map(Vec::len).max().unwrap_or(0);
+        for row in 0..height {
+            for state in line {
+                let text = state.get(row).map(String::as_str).unwrap_or("");
+                print!("{:<width$} | ", text, width = width);
             }
-            print!(" | ");
+            println!();
         }
         println!();
     }
-}
\ No newline at end of file
+}
@@ -1,62 +1,303 @@
 use serde::{Serialize, Deserialize};
-use crate::layer::Layer;
+use crate::layer::{Layer, LayerConfig, LayerGradients, LayerTrace};
 use crate::activation::Activation;
+use crate::initializer::Initializer;
+use crate::loss::Loss;
+use crate::optimizer::Optimizer;
+use crate::regularization::GradientClipping;
+use rand::Rng;
+use rayon::prelude::*;
 
+// Loss gradients for every layer, in layer order, and for the network input
+#[derive(Clone, Debug)]
+pub struct Gradients {
+    pub layers: Vec<LayerGradients>,
+    pub input: Vec<f32>,
+}
+
+impl Gradients {
+    // L2 norm over the parameter gradients of all layers
+    pub fn norm(&self) -> f32 {
+        self.layers.iter().flat_map(|l| l.iter()).map(|g| g * g).sum::<f32>().sqrt()
+    }
 
+    // Adds other, gradients of the same network, to these
+    pub fn add(&mut self, other: &Gradients) {
+        for (layer, other_layer) in self.layers.iter_mut().zip(other.layers.iter()) {
+            layer.add(other_layer);
+        }
+        self.input.iter_mut().zip(other.input.iter()).for_each(|(a, b)| *a += b);
+    }
+
+    pub fn scale(&mut self, factor: f32) {
+        self.layers.iter_mut().flat_map(|l| l.iter_mut()).for_each(|g| *g *= factor);
+        self.input.iter_mut().for_each(|g| *g *= factor);
+    }
+
+    pub fn clip(&mut self, clipping: GradientClipping) {
+        match clipping {
+            GradientClipping::None => {}
+            GradientClipping::PerElement(threshold) => {
+                self.layers.iter_mut().flat_map(|l| l.iter_mut()).for_each(|g| *g = g.clamp(-threshold, threshold));
+            }
+            GradientClipping::GlobalNorm(max_norm) => {
+                let norm = self.norm();
+                if norm > max_norm {
+                    let scale = max_norm / norm;
+                    self.layers.iter_mut().flat_map(|l| l.iter_mut()).for_each(|g| *g *= scale);
+                }
+            }
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct NeuralNetwork {
     pub layers: Vec<Layer>,
+    #[serde(default)]
+    pub clipping: GradientClipping,
 }
 
 impl NeuralNetwork {
-    pub fn new(sizes: &[usize], activations: &[Activation]) -> Self {
+    // Network with the default layer config and clipping, use NeuralNetwork::builder to change them.
+    // The same rng seed always produces the same network.
+    pub fn new(sizes: &[usize], activations: &[Activation], initializer: Initializer, rng: &mut impl Rng) -> Self {
         assert_eq!(sizes.len() - 1, activations.len(), "Number of activations should be one less than the number of layer sizes");
-    
-        let layers: Vec<Layer> = sizes.windows(2)
+
+        let layer_config = LayerConfig { initializer, ..LayerConfig::default() };
+        sizes[1..].iter()
             .zip(activations.iter())
-            .map(|(window, &activation)| Layer::new(window[0], window[1], activation))
-            .collect();
-    
-        Self { layers }
+            .fold(Self::builder(sizes[0]).layer_config(layer_config), |builder, (&size, &activation)| builder.layer(size, activation))
+            .build(rng)
+    }
+
+    pub fn builder(input_size: usize) -> NetworkBuilder {
+        NetworkBuilder {
+            input_size,
+            layers: Vec::new(),
+            layer_config: LayerConfig::default(),
+            clipping: GradientClipping::default(),
+        }
+    }
+
+    // Learns a separate PReLU slope for every neuron instead of one per layer
+    pub fn with_per_neuron_alphas(mut self) -> Self {
+        self.layers = self.layers.into_iter().map(Layer::with_per_neuron_alphas).collect();
+        self
+    }
+
+    // Moves every parameter a fraction tau towards the one of source (Polyak averaging),
+    // source must have the same architecture
+    pub fn soft_update(&mut self, source: &NeuralNetwork, tau: f32) {
+        for (layer, source_layer) in self.layers.iter_mut().zip(source.layers.iter()) {
+            for (p, s) in layer.parameters_mut().zip(source_layer.parameters()) {
+                *p += tau * (s - *p);
+            }
+        }
     }
 
     pub fn forward(&self, input: &[f32]) -> Vec<f32> {
         self.layers.iter().fold(input.to_vec(), |input, layer| layer.forward(&input))
     }
-    // returns the network output and the activations (outputs) of each layer
-    pub fn forward_activations(&self, input: &[f32]) -> Vec<Vec<f32>> {
-        let mut activations = Vec::new();
-        let _ = self.layers.iter().fold(input.to_vec(), |input, layer| {
-            let layer_output = layer.forward(&input);
-            activations.push(layer_output.clone());
-            layer_output // becomes the input for the next layer
-        });
-        activations
-    }
-
-    pub fn backpropagate(&mut self, input: &[f32], target: &[f32], learning_rate: f32) {
-        let activations = self.forward_activations(input);
-        
-        // Compute the errors for the output layer
-        let mut errors = target.iter()
-            .zip(activations.last().unwrap().iter())
-            .map(|(t, o)| t - o)
-            .collect::<Vec<_>>();
-
-        // Prepend input to activations to use it as the input for the first layer
-        let mut activations_with_input = vec![input.to_vec()];
-        activations_with_input.extend(activations);
-    
+    // Forward pass for a minibatch of `batch` inputs laid out one after another,
+    // every layer handles the whole batch with one matrix multiply
+    pub fn forward_batch(&self, input: &[f32], batch: usize) -> Vec<f32> {
+        self.layers.iter().fold(input.to_vec(), |input, layer| layer.forward_batch(&input, batch))
+    }
+    // returns the pre-activations and outputs of each layer, the last trace holds the network output
+    pub fn forward_trace(&self, input: &[f32]) -> Vec<LayerTrace> {
+        let mut traces: Vec<LayerTrace> = Vec::with_capacity(self.layers.len());
+        for layer in &self.layers {
+            let trace = layer.forward_trace(traces.last().map_or(input, |t| &t.outputs));
+            traces.push(trace);
+        }
+        traces
+    }
+
+    // Gradients of the loss for every layer plus the input, given the gradient of the loss with
+    // respect to the network output. Computed only from the parameters used in the forward pass.
+    pub fn backward(&self, input: &[f32], traces: &[LayerTrace], output_gradient: &[f32]) -> Gradients {
+        // The input of each layer is the output of the one before, the first layer sees the network input
+        let layer_inputs: Vec<&[f32]> = std::iter::once(input)
+            .chain(traces[..traces.len() - 1].iter().map(|t| t.outputs.as_slice()))
+            .collect();
+
+        let mut layers = Vec::with_capacity(self.layers.len());
+        let mut gradient = output_gradient.to_vec();
         // Iterate through each layer in reverse order
-        for (layer, layer_activations) in self.layers.iter_mut().zip(activations_with_input.iter()).rev() {
-            layer.update_weights_and_biases(layer_activations, &errors, learning_rate);
-            
-            // Compute the errors for the next layer
-            errors = layer.weights
-                .iter()
-                .map(|neuron_weights| neuron_weights.iter().zip(errors.iter()).map(|(w, e)| w * e).sum())
-                .collect();
+        for ((layer, trace), layer_input) in self.layers.iter().zip(traces.iter()).zip(layer_inputs).rev() {
+            let (layer_gradients, input_gradient) = layer.backward(layer_input, trace, &gradient);
+            layers.push(layer_gradients);
+            gradient = input_gradient;
+        }
+        layers.reverse();
+        Gradients { layers, input: gradient }
+    }
+
+    // Clips the gradients as configured and lets the optimizer take one regularized step
+    pub fn apply_gradients(&mut self, gradients: &Gradients, optimizer: &mut impl Optimizer) {
+        let mut gradients = gradients.clone();
+        gradients.clip(self.clipping);
+        optimizer.begin_step();
+        for (layer_index, (layer, layer_gradients)) in self.layers.iter_mut().zip(gradients.layers.iter()).enumerate() {
+            layer.apply_gradients(layer_gradients, layer_index * Layer::PARAMETER_GROUPS, optimizer);
         }
     }
-}
\ No newline at end of file
+
+    // One optimizer step on the loss between the network output and target,
+    // returns the loss before the step
+    pub fn backpropagate(&mut self, input: &[f32], target: &[f32], loss: &impl Loss, optimizer: &mut impl Optimizer) -> f32 {
+        let traces = self.forward_trace(input);
+        let output = &traces.last().unwrap().outputs;
+        let loss_value = loss.loss(output, target);
+        let output_gradient = loss.gradient(output, target);
+        let gradients = self.backward(input, &traces, &output_gradient);
+        self.apply_gradients(&gradients, optimizer);
+        loss_value
+    }
+
+    // Gradients averaged over a minibatch and the mean loss, every sample runs its forward and
+    // backward pass in parallel. The input gradients are averaged as well. With sample_weights
+    // (e.g. importance-sampling weights) each sample's loss is scaled by its weight.
+    pub fn batch_gradients(&self, inputs: &[Vec<f32>], targets: &[Vec<f32>], sample_weights: Option<&[f32]>, loss: &impl Loss) -> (Gradients, f32) {
+        assert_eq!(inputs.len(), targets.len(), "Every input needs a target");
+        assert!(!inputs.is_empty(), "Empty minibatch");
+
+        let (mut gradients, loss_sum) = inputs.par_iter()
+            .zip(targets.par_iter())
+            .enumerate()
+            .map(|(index, (input, target))| {
+                let weight = sample_weights.map_or(1.0, |weights| weights[index]);
+                let traces = self.forward_trace(input);
+                let output = &traces.last().unwrap().outputs;
+                let loss_value = loss.loss(output, target) * weight;
+                let output_gradient: Vec<f32> = loss.gradient(output, target).iter().map(|g| g * weight).collect();
+                (self.backward(input, &traces, &output_gradient), loss_value)
+            })
+            .reduce_with(|(mut gradients, loss_a), (other, loss_b)| {
+                gradients.add(&other);
+                (gradients, loss_a + loss_b)
+            })
+            .unwrap();
+        let batch = inputs.len() as f32;
+        gradients.scale(1.0 / batch);
+        (gradients, loss_sum / batch)
+    }
+
+    // One optimizer step on the loss averaged over the minibatch, returns the mean loss before the step
+    pub fn backpropagate_batch(&mut self, inputs: &[Vec<f32>], targets: &[Vec<f32>], sample_weights: Option<&[f32]>, loss: &impl Loss, optimizer: &mut impl Optimizer) -> f32 {
+        let (gradients, loss_value) = self.batch_gradients(inputs, targets, sample_weights, loss);
+        self.apply_gradients(&gradients, optimizer);
+        loss_value
+    }
+}
+
+// Builds a network layer by layer, each layer with its own LayerConfig if needed
+pub struct NetworkBuilder {
+    input_size: usize,
+    layers: Vec<(usize, Activation, LayerConfig)>,
+    layer_config: LayerConfig,
+    clipping: GradientClipping,
+}
+
+impl NetworkBuilder {
+    // Config used by every following call to layer
+    pub fn layer_config(mut self, config: LayerConfig) -> Self {
+        self.layer_config = config;
+        self
+    }
+
+    pub fn clipping(mut self, clipping: GradientClipping) -> Self {
+        self.clipping = clipping;
+        self
+    }
+
+    pub fn layer(self, size: usize, activation: Activation) -> Self {
+        let config = self.layer_config;
+        self.layer_with(size, activation, config)
+    }
+
+    pub fn layer_with(mut self, size: usize, activation: Activation, config: LayerConfig) -> Self {
+        self.layers.push((size, activation, config));
+        self
+    }
+
+    pub fn build(self, rng: &mut impl Rng) -> NeuralNetwork {
+        let mut input_size = self.input_size;
+        let layers = self.layers.into_iter()
+            .map(|(size, activation, config)| {
+                let layer = Layer::new(input_size, size, activation, config, rng);
+                input_size = size;
+                layer
+            })
+            .collect();
+        NeuralNetwork { layers, clipping: self.clipping }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    // Every kind of layer the backward pass handles differently: element-wise activations, a
+    // shared and a per-neuron PReLU slope (some above 1), Softmax and LogSoftmax
+    fn network() -> NeuralNetwork {
+        let mut network = NeuralNetwork::builder(4)
+            .layer(6, Activation::Tanh)
+            .layer(6, Activation::ParametricReLU(1.5))
+            .layer(6, Activation::ParametricReLU(0.25))
+            .layer(5, Activation::Sigmoid)
+            .layer(5, Activation::Softmax)
+            .layer(3, Activation::LogSoftmax)
+            .build(&mut StdRng::seed_from_u64(3));
+        network.layers[2] = network.layers[2].clone().with_per_neuron_alphas();
+        network.layers[2].alphas = vec![0.1, 0.5, 1.5, -0.3, 0.9, 2.0];
+        network
+    }
+
+    // A linear loss, so the check only depends on the network: sum of output * weight
+    const OUTPUT_WEIGHTS: [f32; 3] = [0.7, -1.3, 0.4];
+
+    fn loss(network: &NeuralNetwork, input: &[f32]) -> f32 {
+        network.forward(input).iter().zip(OUTPUT_WEIGHTS).map(|(y, w)| y * w).sum()
+    }
+
+    fn assert_close(numeric: f32, analytic: f32, what: &str) {
+        assert!(
+            (numeric - analytic).abs() < 1e-3 + 1e-2 * analytic.abs(),
+            "{}: finite difference {} vs backward {}", what, numeric, analytic
+        );
+    }
+
+    #[test]
+    fn backward_matches_finite_differences() {
+        let h = 1e-3;
+        let network = network();
+        let input = [0.9, -1.2, 0.4, -0.6];
+        let gradients = network.backward(&input, &network.forward_trace(&input), &OUTPUT_WEIGHTS);
+
+        for (layer_index, layer_gradients) in gradients.layers.iter().enumerate() {
+            for (parameter_index, analytic) in layer_gradients.iter().enumerate() {
+                let shifted = |delta: f32| {
+                    let mut network = network.clone();
+                    *network.layers[layer_index].parameters_mut().nth(parameter_index).unwrap() += delta;
+                    loss(&network, &input)
+                };
+                let numeric = (shifted(h) - shifted(-h)) / (2.0 * h);
+                assert_close(numeric, *analytic, &format!("layer {} parameter {}", layer_index, parameter_index));
+            }
+        }
+
+        for (index, analytic) in gradients.input.iter().enumerate() {
+            let shifted = |delta: f32| {
+                let mut input = input;
+                input[index] += delta;
+                loss(&network, &input)
+            };
+            let numeric = (shifted(h) - shifted(-h)) / (2.0 * h);
+            assert_close(numeric, *analytic, &format!("input {}", index));
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use serde::{Serialize, Deserialize};
+
+// Turns gradients into parameter updates. The network hands its parameters over in groups
+// (weights, biases, PReLU slopes of every layer); `slot` identifies a group across steps so
+// the optimizer can keep per-parameter state for it.
+pub trait Optimizer {
+    fn learning_rate(&self) -> f32;
+
+    // Called once per step, before the groups are updated
+    fn begin_step(&mut self) {}
+
+    fn update(&mut self, slot: usize, params: &mut [f32], gradients: &[f32]);
+}
+
+// The state buffer of a slot, created zeroed the first time the slot is seen
+fn slot_state(buffers: &mut Vec<Vec<f32>>, slot: usize, len: usize) -> &mut Vec<f32> {
+    if buffers.len() <= slot {
+        buffers.resize(slot + 1, Vec::new());
+    }
+    let buffer = &mut buffers[slot];
+    if buffer.len() != len {
+        *buffer = vec![0.0; len];
+    }
+    buffer
+}
+
+// Stochastic gradient descent, optionally with (Nesterov) momentum
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Sgd {
+    pub learning_rate: f32,
+    pub momentum: f32,
+    pub nesterov: bool,
+    velocity: Vec<Vec<f32>>,
+}
+
+impl Sgd {
+    pub fn new(learning_rate: f32) -> Self {
+        Self::momentum(learning_rate, 0.0)
+    }
+
+    pub fn momentum(learning_rate: f32, momentum: f32) -> Self {
+        Self { learning_rate, momentum, nesterov: false, velocity: Vec::new() }
+    }
+
+    pub fn nesterov(learning_rate: f32, momentum: f32) -> Self {
+        Self { nesterov: true, ..Self::momentum(learning_rate, momentum) }
+    }
+}
+
+impl Optimizer for Sgd {
+    fn learning_rate(&self) -> f32 {
+        self.learning_rate
+    }
+
+    fn update(&mut self, slot: usize, params: &mut [f32], gradients: &[f32]) {
+        if self.momentum == 0.0 {
+            params.iter_mut().zip(gradients.iter()).for_each(|(p, g)| *p -= self.learning_rate * g);
+            return;
+        }
+        let velocity = slot_state(&mut self.velocity, slot, params.len());
+        for ((p, g), v) in params.iter_mut().zip(gradients.iter()).zip(velocity.iter_mut()) {
+            *v = self.momentum * *v + g;
+            let step = if self.nesterov { g + self.momentum * *v } else { *v };
+            *p -= self.learning_rate * step;
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RmsProp {
+    pub learning_rate: f32,
+    pub decay: f32,
+    pub epsilon: f32,
+    mean_square: Vec<Vec<f32>>,
+}
+
+impl RmsProp {
+    pub fn new(learning_rate: f32) -> Self {
+        Self { learning_rate, decay: 0.9, epsilon: 1e-8, mean_square: Vec::new() }
+    }
+}
+
+impl Optimizer for RmsProp {
+    fn learning_rate(&self) -> f32 {
+        self.learning_rate
+    }
+
+    fn update(&mut self, slot: usize, params: &mut [f32], gradients: &[f32]) {
+        let mean_square = slot_state(&mut self.mean_square, slot, params.len());
+        for ((p, g), s) in params.iter_mut().zip(gradients.iter()).zip(mean_square.iter_mut()) {
+            *s = self.decay * *s + (1.0 - self.decay) * g * g;
+            *p -= self.learning_rate * g / (s.sqrt() + self.epsilon);
+        }
+    }
+}
+
+// Adam, or AdamW when weight_decay is set: the decay shrinks the parameters directly
+// instead of going through the moment estimates
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Adam {
+    pub learning_rate: f32,
+    pub beta1: f32,
+    pub beta2: f32,
+    pub epsilon: f32,
+    pub weight_decay: f32,
+    step: i32,
+    first_moment: Vec<Vec<f32>>,
+    second_moment: Vec<Vec<f32>>,
+}
+
+impl Adam {
+    pub fn new(learning_rate: f32) -> Self {
+        Self {
+            learning_rate,
+            beta1: 0.9,
+            beta2: 0.999,
+            epsilon: 1e-8,
+            weight_decay: 0.0,
+            step: 0,
+            first_moment: Vec::new(),
+            second_moment: Vec::new(),
+        }
+    }
+
+    pub fn adamw(learning_rate: f32, weight_decay: f32) -> Self {
+        Self { weight_decay, ..Self::new(learning_rate) }
+    }
+}
+
+impl Optimizer for Adam {
+    fn learning_rate(&self) -> f32 {
+        self.learning_rate
+    }
+
+    fn begin_step(&mut self) {
+        self.step += 1;
+    }
+
+    fn update(&mut self, slot: usize, params: &mut [f32], gradients: &[f32]) {
+        // bias correction of the zero initialised moments
+        let step = self.step.max(1);
+        let correction1 = 1.0 - self.beta1.powi(step);
+        let correction2 = 1.0 - self.beta2.powi(step);
+        slot_state(&mut self.first_moment, slot, params.len());
+        slot_state(&mut self.second_moment, slot, params.len());
+        let moments = self.first_moment[slot].iter_mut().zip(self.second_moment[slot].iter_mut());
+        for ((p, g), (m, v)) in params.iter_mut().zip(gradients.iter()).zip(moments) {
+            *m = self.beta1 * *m + (1.0 - self.beta1) * g;
+            *v = self.beta2 * *v + (1.0 - self.beta2) * g * g;
+            let m_hat = *m / correction1;
+            let v_hat = *v / correction2;
+            *p -= self.learning_rate * (m_hat / (v_hat.sqrt() + self.epsilon) + self.weight_decay * *p);
+        }
+    }
+}
+
+// Any of the optimizers above, chosen at runtime. Serializes with its state, so a checkpointed
+// optimizer resumes as the same kind.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum AnyOptimizer {
+    Sgd(Sgd),
+    RmsProp(RmsProp),
+    Adam(Adam),
+}
+
+impl Optimizer for AnyOptimizer {
+    fn learning_rate(&self) -> f32 {
+        match self {
+            AnyOptimizer::Sgd(optimizer) => optimizer.learning_rate(),
+            AnyOptimizer::RmsProp(optimizer) => optimizer.learning_rate(),
+            AnyOptimizer::Adam(optimizer) => optimizer.learning_rate(),
+        }
+    }
+
+    fn begin_step(&mut self) {
+        match self {
+            AnyOptimizer::Sgd(optimizer) => optimizer.begin_step(),
+            AnyOptimizer::RmsProp(optimizer) => optimizer.begin_step(),
+            AnyOptimizer::Adam(optimizer) => optimizer.begin_step(),
+        }
+    }
+
+    fn update(&mut self, slot: usize, params: &mut [f32], gradients: &[f32]) {
+        match self {
+            AnyOptimizer::Sgd(optimizer) => optimizer.update(slot, params, gradients),
+            AnyOptimizer::RmsProp(optimizer) => optimizer.update(slot, params, gradients),
+            AnyOptimizer::Adam(optimizer) => optimizer.update(slot, params, gradients),
+        }
+    }
+}
+
+// The hyperparameters of an optimizer without its state, as written in a training config
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum OptimizerConfig {
+    Sgd { learning_rate: f32, momentum: f32, nesterov: bool },
+    RmsProp { learning_rate: f32, decay: f32 },
+    Adam { learning_rate: f32, weight_decay: f32 },
+}
+
+impl OptimizerConfig {
+    pub fn build(&self) -> AnyOptimizer {
+        match *self {
+            OptimizerConfig::Sgd { learning_rate, momentum, nesterov } => {
+                AnyOptimizer::Sgd(Sgd { nesterov, ..Sgd::momentum(learning_rate, momentum) })
+            }
+            OptimizerConfig::RmsProp { learning_rate, decay } => {
+                AnyOptimizer::RmsProp(RmsProp { decay, ..RmsProp::new(learning_rate) })
+            }
+            OptimizerConfig::Adam { learning_rate, weight_decay } => {
+                AnyOptimizer::Adam(Adam::adamw(learning_rate, weight_decay))
+            }
+        }
+    }
+}
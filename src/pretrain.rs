@@ -0,0 +1,144 @@
+use crate::config::TrainConfig;
+use crate::game::{Bits, Board, Solver, board_to_input, reachable_positions};
+use crate::loss::{Loss, LossFunction};
+use crate::network::NeuralNetwork;
+use crate::optimizer::OptimizerConfig;
+use crate::train::config_rng;
+use rand::seq::SliceRandom;
+use serde::{Serialize, Deserialize};
+
+// What the network learns to predict for every position
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum Labels {
+    // Q-value of every move on the reward scale of the training config: win, draw or loss of the
+    // move under perfect play, illegal_move for occupied cells. Keeps the network usable for DQN.
+    MoveValues,
+    // Uniform distribution over the minimax-optimal moves, trained with softmax cross-entropy
+    OptimalMoves,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct PretrainConfig {
+    pub labels: Labels,
+    pub epochs: usize,
+    pub batch_size: usize,
+    // share of the positions held out to decide when to stop
+    pub validation_split: f32,
+    // epochs without a better validation loss before training stops
+    pub patience: usize,
+    pub optimizer: OptimizerConfig,
+    // loss for MoveValues labels. Mse rather than the Huber loss of self-play, whose capped
+    // gradient takes ages to reach the illegal_move targets.
+    pub value_loss: LossFunction,
+}
+
+impl Default for PretrainConfig {
+    fn default() -> Self {
+        Self {
+            labels: Labels::MoveValues,
+            epochs: 500,
+            batch_size: 64,
+            validation_split: 0.1,
+            patience: 20,
+            optimizer: OptimizerConfig::Adam { learning_rate: 0.01, weight_decay: 0.0 },
+            value_loss: LossFunction::Mse,
+        }
+    }
+}
+
+pub struct Sample {
+    pub input: Vec<f32>,
+    pub target: Vec<f32>,
+}
+
+// One labelled sample for every reachable, undecided position of the empty board
+pub fn build_dataset<B: Bits>(labels: Labels, board: &Board<B>, config: &TrainConfig) -> Vec<Sample> {
+    let mut solver = Solver::new();
+    let cells = board.size();
+    reachable_positions(board).into_iter()
+        .map(|(board, player)| {
+            let mut target = match labels {
+                Labels::MoveValues => vec![config.rewards.illegal_move; cells],
+                Labels::OptimalMoves => vec![0.0; cells],
+            };
+            match labels {
+                Labels::MoveValues => {
+                    for (action, value) in solver.move_values(&board, player) {
+                        target[action] = match value.signum() {
+                            1 => config.rewards.win,
+                            -1 => config.rewards.loss,
+                            _ => config.rewards.draw,
+                        };
+                    }
+                }
+                Labels::OptimalMoves => {
+                    let optimal = solver.optimal_moves(&board, player);
+                    for &action in &optimal {
+                        target[action] = 1.0 / optimal.len() as f32;
+                    }
+                }
+            }
+            Sample { input: board_to_input(&board, player), target }
+        })
+        .collect()
+}
+
+// The loss matching the labels, cross-entropy for optimal move distributions
+pub fn pretrain_loss(config: &PretrainConfig) -> LossFunction {
+    match config.labels {
+        Labels::MoveValues => config.value_loss,
+        Labels::OptimalMoves => LossFunction::SoftmaxCrossEntropy,
+    }
+}
+
+// Supervised training on the minimax-labelled positions with early stopping on the validation
+// loss. Returns the network of the epoch with the best validation loss.
+pub fn pretrain<B: Bits>(mut network: NeuralNetwork, board: &Board<B>, config: &TrainConfig) -> NeuralNetwork {
+    let settings = &config.pretrain;
+    let mut rng = config_rng(config);
+    let loss = pretrain_loss(settings);
+    let mut optimizer = settings.optimizer.build();
+
+    let mut samples = build_dataset(settings.labels, board, config);
+    samples.shuffle(&mut rng);
+    let validation_size = ((samples.len() as f32 * settings.validation_split) as usize).min(samples.len() - 1);
+    let validation = samples.split_off(samples.len() - validation_size);
+    let mut training = samples;
+
+    let mut best = (f32::INFINITY, network.clone());
+    let mut epochs_without_improvement = 0;
+    for epoch in 0..settings.epochs {
+        training.shuffle(&mut rng);
+        let mut training_loss = 0.0;
+        for batch in training.chunks(settings.batch_size.max(1)) {
+            let inputs: Vec<Vec<f32>> = batch.iter().map(|sample| sample.input.clone()).collect();
+            let targets: Vec<Vec<f32>> = batch.iter().map(|sample| sample.target.clone()).collect();
+            training_loss += network.backpropagate_batch(&inputs, &targets, None, &loss, &mut optimizer) * batch.len() as f32;
+        }
+        training_loss /= training.len() as f32;
+
+        let validation_loss = mean_loss(&network, &validation, &loss).unwrap_or(training_loss);
+        println!("Epoch {}/{}: training loss {:.5}, validation loss {:.5}", epoch + 1, settings.epochs, training_loss, validation_loss);
+
+        if validation_loss < best.0 {
+            best = (validation_loss, network.clone());
+            epochs_without_improvement = 0;
+        } else {
+            epochs_without_improvement += 1;
+            if epochs_without_improvement >= settings.patience {
+                println!("No improvement for {} epochs, stopping", settings.patience);
+                break;
+            }
+        }
+    }
+    best.1
+}
+
+fn mean_loss(network: &NeuralNetwork, samples: &[Sample], loss: &impl Loss) -> Option<f32> {
+    if samples.is_empty() {
+        return None;
+    }
+    let total: f32 = samples.iter().map(|sample| loss.loss(&network.forward(&sample.input), &sample.target)).sum();
+    Some(total / samples.len() as f32)
+}
@@ -0,0 +1,55 @@
+use serde::{Serialize, Deserialize};
+
+// Penalty on the parameter size, added to the loss gradient of every regularized parameter
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum Regularizer {
+    None,
+    L1(f32),
+    L2(f32),
+    L1L2 { l1: f32, l2: f32 },
+    // Shrinks the parameters directly after the update instead of going through the gradient
+    // (decoupled weight decay), so the decay does not get rescaled by adaptive optimizers
+    WeightDecay(f32),
+}
+
+impl Regularizer {
+    // Gradient of the penalty for parameter value w, added to the loss gradient
+    pub fn gradient(&self, w: f32) -> f32 {
+        match self {
+            Regularizer::L1(l1) => l1 * w.signum(),
+            Regularizer::L2(l2) => l2 * w,
+            Regularizer::L1L2 { l1, l2 } => l1 * w.signum() + l2 * w,
+            Regularizer::None | Regularizer::WeightDecay(_) => 0.0,
+        }
+    }
+
+    // Factor of the decoupled decay, the update subtracts learning_rate * decay * w
+    pub fn decay(&self) -> f32 {
+        match self {
+            Regularizer::WeightDecay(decay) => *decay,
+            _ => 0.0,
+        }
+    }
+}
+
+impl Default for Regularizer {
+    fn default() -> Self {
+        Regularizer::L1L2 { l1: 0.001, l2: 0.001 }
+    }
+}
+
+// Keeps single updates from exploding, applied to the gradients of the whole network at once
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum GradientClipping {
+    None,
+    // Clamp every gradient entry to [-threshold, threshold]
+    PerElement(f32),
+    // Rescale all gradients together when their L2 norm exceeds the limit
+    GlobalNorm(f32),
+}
+
+impl Default for GradientClipping {
+    fn default() -> Self {
+        GradientClipping::PerElement(10.0)
+    }
+}
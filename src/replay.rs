@@ -0,0 +1,264 @@
+use rand::RngCore;
+use rand::Rng;
+
+// Decides which stored experiences end up in a minibatch. Works on slots of the replay buffer's
+// ring, a slot is reused once the buffer wraps around.
+pub trait Sampler: Send {
+    // A new item was written into slot
+    fn insert(&mut self, slot: usize);
+
+    // Draws count of the len filled slots together with their importance-sampling weights
+    fn sample(&mut self, count: usize, len: usize, rng: &mut dyn RngCore) -> Vec<(usize, f32)>;
+
+    // New TD errors for slots that were just trained on
+    fn update_priorities(&mut self, _slots: &[usize], _td_errors: &[f32]) {}
+}
+
+// Every stored item is equally likely, drawn without replacement, all weights are 1
+pub struct UniformSampler;
+
+impl Sampler for UniformSampler {
+    fn insert(&mut self, _slot: usize) {}
+
+    fn sample(&mut self, count: usize, len: usize, rng: &mut dyn RngCore) -> Vec<(usize, f32)> {
+        rand::seq::index::sample(rng, len, count.min(len))
+            .into_iter()
+            .map(|slot| (slot, 1.0))
+            .collect()
+    }
+}
+
+// Binary tree over the slot priorities where every node holds the sum of its children,
+// so drawing proportionally to priority and updating one priority both take O(log n)
+pub struct SumTree {
+    leaves: usize,
+    nodes: Vec<f32>,
+}
+
+impl SumTree {
+    pub fn new(capacity: usize) -> Self {
+        let leaves = capacity.max(1).next_power_of_two();
+        Self { leaves, nodes: vec![0.0; 2 * leaves] }
+    }
+
+    pub fn total(&self) -> f32 {
+        self.nodes[1]
+    }
+
+    pub fn get(&self, slot: usize) -> f32 {
+        self.nodes[self.leaves + slot]
+    }
+
+    pub fn set(&mut self, slot: usize, priority: f32) {
+        let mut node = self.leaves + slot;
+        self.nodes[node] = priority;
+        while node > 1 {
+            node /= 2;
+            self.nodes[node] = self.nodes[2 * node] + self.nodes[2 * node + 1];
+        }
+    }
+
+    // The slot whose cumulative priority range contains value, for value in [0, total)
+    pub fn find(&self, mut value: f32) -> usize {
+        let mut node = 1;
+        while node < self.leaves {
+            let left = 2 * node;
+            if value < self.nodes[left] || self.nodes[left + 1] <= 0.0 {
+                node = left;
+            } else {
+                value -= self.nodes[left];
+                node = left + 1;
+            }
+        }
+        node - self.leaves
+    }
+}
+
+// Prioritized experience replay: slots are drawn with probability proportional to
+// (|td error| + epsilon)^alpha. The bias this introduces is corrected by the importance-sampling
+// weights (len * P(slot))^-beta, normalized by the largest weight of the batch. Beta is annealed
+// towards 1 by beta_increment per sampled batch.
+pub struct PrioritizedSampler {
+    pub alpha: f32,
+    pub beta: f32,
+    pub beta_increment: f32,
+    pub epsilon: f32,
+    tree: SumTree,
+    max_priority: f32,
+}
+
+impl PrioritizedSampler {
+    pub fn new(capacity: usize, alpha: f32, beta: f32, beta_increment: f32) -> Self {
+        Self { alpha, beta, beta_increment, epsilon: 1e-3, tree: SumTree::new(capacity), max_priority: 1.0 }
+    }
+}
+
+impl Sampler for PrioritizedSampler {
+    // New experiences get the highest priority seen so far, so each is trained on at least once
+    fn insert(&mut self, slot: usize) {
+        self.tree.set(slot, self.max_priority);
+    }
+
+    fn sample(&mut self, count: usize, len: usize, rng: &mut dyn RngCore) -> Vec<(usize, f32)> {
+        let total = self.tree.total();
+        // one draw per equally sized segment of the total priority keeps the batch diverse
+        let segment = total / count as f32;
+        let slots: Vec<usize> = (0..count)
+            .map(|i| {
+                let value = (i as f32 + rng.gen::<f32>()) * segment;
+                self.tree.find(value.min(total * (1.0 - f32::EPSILON))).min(len - 1)
+            })
+            .collect();
+
+        let weights: Vec<f32> = slots.iter()
+            .map(|&slot| {
+                let probability = self.tree.get(slot) / total;
+                (len as f32 * probability).powf(-self.beta)
+            })
+            .collect();
+        let max_weight = weights.iter().fold(f32::MIN_POSITIVE, |acc, w| acc.max(*w));
+        self.beta = (self.beta + self.beta_increment).min(1.0);
+
+        slots.into_iter().zip(weights).map(|(slot, weight)| (slot, weight / max_weight)).collect()
+    }
+
+    fn update_priorities(&mut self, slots: &[usize], td_errors: &[f32]) {
+        for (&slot, td_error) in slots.iter().zip(td_errors.iter()) {
+            let priority = (td_error.abs() + self.epsilon).powf(self.alpha);
+            self.max_priority = self.max_priority.max(priority);
+            self.tree.set(slot, priority);
+        }
+    }
+}
+
+// A drawn minibatch: the buffer slots and their importance-sampling weights
+pub struct ReplayBatch {
+    pub slots: Vec<usize>,
+    pub weights: Vec<f32>,
+}
+
+// Fixed-capacity replay memory. Once full, every push overwrites the oldest item in O(1).
+pub struct ReplayBuffer<T> {
+    items: Vec<T>,
+    capacity: usize,
+    next: usize,
+    sampler: Box<dyn Sampler>,
+}
+
+impl<T> ReplayBuffer<T> {
+    pub fn new(capacity: usize, sampler: Box<dyn Sampler>) -> Self {
+        assert!(capacity > 0, "Replay buffer needs room for at least one item");
+        Self { items: Vec::with_capacity(capacity), capacity, next: 0, sampler }
+    }
+
+    pub fn uniform(capacity: usize) -> Self {
+        Self::new(capacity, Box::new(UniformSampler))
+    }
+
+    pub fn prioritized(capacity: usize, alpha: f32, beta: f32, beta_increment: f32) -> Self {
+        Self::new(capacity, Box::new(PrioritizedSampler::new(capacity, alpha, beta, beta_increment)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    // Stores item, returns the oldest item if it had to make room for it
+    pub fn push(&mut self, item: T) -> Option<T> {
+        let slot = self.next;
+        let evicted = if self.items.len() < self.capacity {
+            self.items.push(item);
+            None
+        } else {
+            Some(std::mem::replace(&mut self.items[slot], item))
+        };
+        self.sampler.insert(slot);
+        self.next = (slot + 1) % self.capacity;
+        evicted
+    }
+
+    pub fn get(&self, slot: usize) -> &T {
+        &self.items[slot]
+    }
+
+    pub fn sample(&mut self, count: usize, rng: &mut dyn RngCore) -> ReplayBatch {
+        let (slots, weights) = self.sampler.sample(count, self.items.len(), rng).into_iter().unzip();
+        ReplayBatch { slots, weights }
+    }
+
+    // Feeds the TD errors of a trained batch back to the sampler
+    pub fn update_priorities(&mut self, slots: &[usize], td_errors: &[f32]) {
+        self.sampler.update_priorities(slots, td_errors);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    // Cumulative ranges: slot 0 [0, 1), slot 2 [1, 3), slot 3 [3, 6), slots 1 and 4 hold nothing
+    fn tree() -> SumTree {
+        let mut tree = SumTree::new(5);
+        for (slot, priority) in [1.0, 0.0, 2.0, 3.0, 0.0].into_iter().enumerate() {
+            tree.set(slot, priority);
+        }
+        tree
+    }
+
+    #[test]
+    fn find_returns_the_slot_of_the_cumulative_range() {
+        let tree = tree();
+        assert_eq!(tree.total(), 6.0);
+        for (value, slot) in [(0.0, 0), (0.999, 0), (1.0, 2), (2.999, 2), (3.0, 3), (5.999, 3)] {
+            assert_eq!(tree.find(value), slot, "value {}", value);
+        }
+        // values drifted to or past the total land on the last slot with a priority
+        assert_eq!(tree.find(6.0), 3);
+        assert_eq!(tree.find(6.5), 3);
+    }
+
+    #[test]
+    fn zero_priority_slots_are_never_drawn() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut sampler = PrioritizedSampler::new(8, 0.6, 0.4, 0.0);
+        for slot in 0..8 {
+            sampler.insert(slot);
+        }
+        for slot in [0, 3, 7] {
+            sampler.tree.set(slot, 0.0);
+        }
+        for _ in 0..1000 {
+            for (slot, _) in sampler.sample(16, 8, &mut rng) {
+                assert!(![0, 3, 7].contains(&slot), "drew empty slot {}", slot);
+            }
+        }
+    }
+
+    #[test]
+    fn weights_are_normalized_importance_sampling_weights() {
+        let mut rng = StdRng::seed_from_u64(11);
+        let (len, beta) = (5, 0.4);
+        let mut sampler = PrioritizedSampler::new(len, 0.6, beta, 0.1);
+        for slot in 0..len {
+            sampler.insert(slot);
+        }
+        sampler.update_priorities(&[0, 1, 2, 3, 4], &[0.1, 2.0, 0.5, 4.0, 1.0]);
+
+        let total = sampler.tree.total();
+        let batch = sampler.sample(4, len, &mut rng);
+        let expected: Vec<f32> = batch.iter()
+            .map(|&(slot, _)| (len as f32 * sampler.tree.get(slot) / total).powf(-beta))
+            .collect();
+        let max = expected.iter().fold(0.0f32, |acc, w| acc.max(*w));
+        for (&(slot, weight), expected) in batch.iter().zip(&expected) {
+            assert!((weight - expected / max).abs() < 1e-6, "slot {}: weight {} vs {}", slot, weight, expected / max);
+        }
+        assert!((sampler.beta - (beta + 0.1)).abs() < 1e-6);
+    }
+}
@@ -1,30 +1,120 @@
 use crate::activation::Activation;
-use crate::game::{Board, BOARD_SIZE, empty_board, check_winner, make_move, is_full};
+use crate::config::{Augmentation, ReplayConfig, TrainConfig};
+use crate::game::{Game, Outcome, Symmetry};
+// the observation encoding of m,n,k boards lives in game
+pub use crate::game::{board_to_input, transform_input};
 use crate::network::NeuralNetwork;
-use rand::{Rng};
+use crate::optimizer::{Optimizer, OptimizerConfig};
+use crate::replay::ReplayBuffer;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use rand::seq::SliceRandom;
+use std::collections::HashSet;
 use std::fs::File;
 use std::io::{BufReader, BufWriter};
-// use rayon::prelude::*;
+use rayon::prelude::*;
 // use std::sync::Mutex;
 //use itertools::{Itertools, Either};
 
-const DISCOUNT_FACTOR: f32 = 0.9;
-const INITIAL_EPSILON: f32 = 1.0;
-const FINAL_EPSILON: f32 = 0.1;
-const EPSILON_DECAY: f32 = 0.9999;
-const BUFFER_CAPACITY: usize = 10000;
-const BATCH_SIZE: usize = 300;
-const LEARNING_RATE: f32 = 0.0001;
-const EPISODES: usize = 50000;
+// How the target network follows the online network
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum TargetSync {
+    // Copy the online network every `interval` updates
+    Hard { interval: usize },
+    // Move a fraction tau towards the online network after every update
+    Soft { tau: f32 },
+}
+
+// Frozen copy of the online network used to compute the bootstrap targets, so the targets do
+// not shift with every update of the network that is chasing them
+pub struct TargetNetwork {
+    pub network: NeuralNetwork,
+    sync: TargetSync,
+    updates: usize,
+}
+
+impl TargetNetwork {
+    pub fn new(online: &NeuralNetwork, sync: TargetSync) -> Self {
+        Self { network: online.clone(), sync, updates: 0 }
+    }
+
+    // Call after every update of the online network
+    pub fn update(&mut self, online: &NeuralNetwork) {
+        self.updates += 1;
+        match self.sync {
+            TargetSync::Hard { interval } => {
+                if self.updates.is_multiple_of(interval.max(1)) {
+                    self.network = online.clone();
+                }
+            }
+            TargetSync::Soft { tau } => self.network.soft_update(online, tau),
+        }
+    }
 
+    // Value of the best next action. With double DQN the online network picks the action and the
+    // target network evaluates it, which removes the overestimation of taking the max of noisy values.
+    pub fn next_value(&self, online: &NeuralNetwork, next_state: &[f32], double_dqn: bool) -> f32 {
+        let target_q_values = self.network.forward(next_state);
+        if double_dqn {
+            let online_q_values = online.forward(next_state);
+            let best_action = online_q_values.iter().enumerate()
+                .max_by(|a, b| a.1.partial_cmp(b.1).unwrap())
+                .map(|(idx, _)| idx)
+                .unwrap();
+            target_q_values[best_action]
+        } else {
+            target_q_values.iter().fold(f32::NEG_INFINITY, |acc, x| acc.max(*x))
+        }
+    }
+}
+
+// One move of self-play. The state is seen by the player who moved, next_state by the player to
+// move next, the opponent. So the value of next_state is the opponent's and enters the target
+// negated (negamax): target = reward - discount * max Q(next_state).
 #[derive(Clone, PartialEq, Debug)]
 struct Experience {
     state: Vec<f32>,
     action: usize,
     reward: f32,
     next_state: Vec<f32>,
-    draw: bool,
+    // the game ended after this move (or, for the loser, after the opponent's reply), nothing to bootstrap
+    terminal: bool,
+    // the transforms that bring the game before and after the move into canonical orientation,
+    // only known with Augmentation::Canonical
+    canonical: Option<Symmetry>,
+    next_canonical: Option<Symmetry>,
+}
+
+impl Experience {
+    // The same move seen in the transformed game
+    fn transformed(&self, symmetry: Symmetry, game: &impl Game) -> Self {
+        Experience {
+            state: game.transform_observation(&self.state, symmetry),
+            action: game.transform_action(self.action, symmetry),
+            next_state: game.transform_observation(&self.next_state, symmetry),
+            canonical: None,
+            next_canonical: None,
+            ..*self
+        }
+    }
+
+    // Both states in canonical orientation. They are transformed separately, the bootstrap only
+    // needs the best value of next_state, which no symmetry changes.
+    fn canonicalized(&self, game: &impl Game) -> Self {
+        let (canonical, next_canonical) = self.canonical.zip(self.next_canonical)
+            .expect("canonical symmetries are recorded with Augmentation::Canonical");
+        Experience {
+            next_state: game.transform_observation(&self.next_state, next_canonical),
+            ..self.transformed(canonical, game)
+        }
+    }
+
+    // Identifies an experience in canonical orientation. The reward is part of it, the same move
+    // can lead to a step or, after the opponent's reply, to a loss.
+    fn key(&self) -> (Vec<bool>, usize, u32, bool) {
+        (self.state.iter().map(|&x| x > 0.5).collect(), self.action, self.reward.to_bits(), self.terminal)
+    }
 }
 
 // impl Experience {
@@ -38,166 +128,231 @@ struct Experience {
 //     }
 // }
 
-pub fn board_to_input(board: &Board, player: char) -> Vec<f32> {
-    let total_size = board.len() * board[0].len();
-    let mut input = vec![0.0; total_size * 2];
-    for (idx, row) in board.iter().enumerate() {
-        for (jdx, cell) in row.iter().enumerate() {
-            let player_index = idx * board.len() + jdx;
-            let oponent_index = total_size + idx * board.len() + jdx;
-            input[player_index] = match *cell {
-                c if c == player => 1.0,
-                _ => 0.0,
-            };
-            input[oponent_index] = match *cell {
-                c if c != player && c != '-' => 1.0,
-                _ => 0.0,
-            };
-        }
-    }
-    input
-}
-
-pub fn epsilon_greedy(network: &NeuralNetwork, state: &Vec<f32>, epsilon: f32, board: &Board, legal_only: bool) -> usize {
-    let mut rng = rand::thread_rng();
+pub fn epsilon_greedy(network: &NeuralNetwork, state: &[f32], epsilon: f32, game: &impl Game, legal_only: bool, rng: &mut impl Rng) -> usize {
     if rng.gen::<f32>() < epsilon {
         if legal_only {
             // Choose a random legal move
-            let mut legal_moves: Vec<usize> = vec![];
-            for i in 0..(board.len() * board[0].len()) {
-                let (row, col) = (i / board.len(), i % board.len());
-                if board[row][col] == '-' {
-                    legal_moves.push(i);
-                }
-            }
-            legal_moves.choose(&mut rng).cloned().unwrap()
+            game.legal_actions().choose(rng).cloned().unwrap()
         } else {
-            rng.gen_range(0 .. board.len() * board.len())
+            rng.gen_range(0 .. game.action_space())
         }
     } else {
         // Choose the move with the highest Q-value among legal moves
         let q_values = network.forward(state);
         if legal_only{
             let mut legal_q_values: Vec<(usize, f32)> = vec![];
-            for i in 0..(board.len() * board.len()) {
-                let (row, col) = (i / board.len(), i % board.len());
-                if board[row][col] == '-' {
-                    legal_q_values.push((i, q_values[i]));
+            for ((i, q_value), legal) in q_values.iter().enumerate().zip(game.action_mask()) {
+                if legal {
+                    legal_q_values.push((i, *q_value));
                 }
             }
             legal_q_values.iter().max_by(|a, b| a.1.partial_cmp(&b.1).unwrap()).map(|&(idx, _)| idx).unwrap()
         }
         else {
-            q_values.iter().enumerate().max_by(|a, b| a.1.partial_cmp(&b.1).unwrap()).map(|(idx, _)| idx).unwrap()
+            q_values.iter().enumerate().max_by(|a, b| a.1.partial_cmp(b.1).unwrap()).map(|(idx, _)| idx).unwrap()
         }
     }
 }
 
-pub fn train(mut network: NeuralNetwork) -> Result<NeuralNetwork, Box<dyn std::error::Error>> {
-    let mut rng = rand::thread_rng();
+// Seeded from the config, or from entropy when the config has no seed
+pub fn config_rng(config: &TrainConfig) -> StdRng {
+    match config.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
+
+// Self-play DQN on game, every episode starts from game.initial_state()
+pub fn train<G: Game>(mut network: NeuralNetwork, game: &G, config: &TrainConfig, optimizer: &mut impl Optimizer) -> Result<NeuralNetwork, Box<dyn std::error::Error>> {
+    let mut rng = config_rng(config);
+    let loss = &config.loss;
+    let rewards = &config.rewards;
     
-    let mut experiences: Vec<Experience> = Vec::new();
-    let mut epsilon = INITIAL_EPSILON;
+    let mut experiences: ReplayBuffer<Experience> = match config.replay {
+        ReplayConfig::Prioritized { alpha, beta, beta_increment } => {
+            ReplayBuffer::prioritized(config.buffer_capacity, alpha, beta, beta_increment)
+        }
+        ReplayConfig::Uniform => ReplayBuffer::uniform(config.buffer_capacity),
+    };
+    // canonical experiences currently in the buffer, only used with Augmentation::Canonical
+    let mut stored: HashSet<(Vec<bool>, usize, u32, bool)> = HashSet::new();
+    let canonicalize = config.augmentation == Augmentation::Canonical;
+    let mut target_network = TargetNetwork::new(&network, config.target_sync);
+    let mut epsilon = config.initial_epsilon;
     // let result_network = Mutex::new(network);
+    let mut loss_sum = 0.0;
+    let mut loss_count = 0;
     
-    for _ in 0..EPISODES {
-        let mut board = empty_board();
-        let mut episode_finished = false;
-        let mut player = 'X';
-        let mut oponent = 'O';
+    for episode in 0..config.episodes {
+        let mut state_of_play = game.initial_state();
 
         let mut episode_experiences: Vec<Experience> = Vec::new();
 
         loop {
-            let state = board_to_input(&board, player);
-            let action = epsilon_greedy(&network, &state, epsilon, &board, false);
-            let (row, col) = (action / BOARD_SIZE, action % BOARD_SIZE);
-
-            let res = make_move(&mut board, player, row, col);
-            let next_state = board_to_input(&board, player);
-
-            let reward;
-            if res.is_err() {
-                reward = -100.0;
-                episode_finished = true;
-            } else if check_winner(&board).is_some() {
-                reward = 10.0;
-                episode_finished = true;
-            } else if is_full(&board) {
-                reward = -0.5;
-                episode_finished = true;
-            } else {
-                reward = -0.1;
+            let player = state_of_play.current_player();
+            let state = state_of_play.observation(player);
+            let canonical = canonicalize.then(|| state_of_play.canonical_symmetry());
+            let action = epsilon_greedy(&network, &state, epsilon, &state_of_play, false, &mut rng);
+
+            let res = state_of_play.apply(action);
+            let next_state = state_of_play.observation(player.opponent());
+            let next_canonical = canonicalize.then(|| state_of_play.canonical_symmetry());
+
+            let outcome = if res.is_ok() { state_of_play.outcome() } else { None };
+            let (reward, terminal) = match outcome {
+                _ if res.is_err() => (rewards.illegal_move, true),
+                Some(Outcome::Win(_)) => (rewards.win, true),
+                Some(Outcome::Draw) => (rewards.draw, true),
+                None => (rewards.step, false),
+            };
+
+            // The opponent's last move allowed this win, it ends the game as a loss for them. A
+            // draw ends it as a draw for both, so the two sides see the same result.
+            let opponent_reward = match outcome {
+                Some(Outcome::Win(_)) => Some(rewards.loss),
+                Some(Outcome::Draw) => Some(rewards.draw),
+                None => None,
+            };
+            if let (Some(opponent_reward), Some(previous_move)) = (opponent_reward, episode_experiences.last_mut()) {
+                previous_move.reward = opponent_reward;
+                previous_move.terminal = true;
             }
-            
+
             episode_experiences.push(Experience {
-                state: state,
+                state,
                 action,
                 reward,
-                next_state: next_state.clone(),
-                draw: is_full(&board) && check_winner(&board).is_none(),
+                next_state,
+                terminal,
+                canonical,
+                next_canonical,
             });
 
-            if episode_finished {
+            if terminal {
                 break;
             }
-
-            // Swap players
-            std::mem::swap(&mut player, &mut oponent);
         }
 
-        // Add all experiences of the current episode to the main experience list
-        experiences.extend(episode_experiences.into_iter());
-
-        // Ensure the buffer does not exceed its capacity
-        while experiences.len() > BUFFER_CAPACITY {
-            experiences.remove(0);
+        // Add all experiences of the current episode to the replay memory, the oldest make room
+        for experience in episode_experiences {
+            match config.augmentation {
+                Augmentation::None => {
+                    experiences.push(experience);
+                }
+                Augmentation::Symmetries => {
+                    for symmetry in game.symmetries() {
+                        experiences.push(experience.transformed(symmetry, game));
+                    }
+                }
+                Augmentation::Canonical => {
+                    let experience = experience.canonicalized(game);
+                    if stored.insert(experience.key()) {
+                        if let Some(evicted) = experiences.push(experience) {
+                            stored.remove(&evicted.key());
+                        }
+                    }
+                }
+            }
         }
 
-        // Train the neural network
-        if experiences.len() >= BATCH_SIZE {
-            for experience in experiences.choose_multiple(&mut rng, BATCH_SIZE){
-                    // if (experience.reward -10.0).abs() < 0.0001 {
-                    //     println!("win");
-                    // }
-                    let q_values = network.forward(&experience.state);
-                    let mut target_q_values = q_values.clone();
-
-                    let next_q_values = network.forward(&experience.next_state);
-                    let max_next_q_value = if experience.draw {0.0}
-                                            else { next_q_values.iter().fold(f32::NEG_INFINITY, |acc, x| acc.max(*x))};
-                    let target_q_value = experience.reward + DISCOUNT_FACTOR * max_next_q_value;
-                    // zero target_q_values
-                    // target_q_values.iter_mut().for_each(|x| *x = 0.0);
+        // Train the neural network on one minibatch, all samples contribute to a single update
+        if experiences.len() >= config.batch_size {
+            let sampled = experiences.sample(config.batch_size, &mut rng);
+            let batch: Vec<&Experience> = sampled.slots.iter().map(|&slot| experiences.get(slot)).collect();
+            let (target_q_values, td_errors): (Vec<Vec<f32>>, Vec<f32>) = batch.par_iter()
+                .map(|experience| {
+                    // only the taken action gets a new target, the other outputs keep their value
+                    let mut target_q_values = network.forward(&experience.state);
+
+                    // the best the opponent can do from next_state is what this move is worth to them
+                    let opponent_value = if experience.terminal {0.0}
+                                         else { target_network.next_value(&network, &experience.next_state, config.double_dqn) };
+                    let target_q_value = experience.reward - config.discount_factor * opponent_value;
+                    let td_error = target_q_value - target_q_values[experience.action];
                     target_q_values[experience.action] = target_q_value;
+                    (target_q_values, td_error)
+                })
+                .unzip();
+            let states: Vec<Vec<f32>> = batch.iter().map(|experience| experience.state.clone()).collect();
 
-                    // Update the neural network using gradient descent
-                    network.backpropagate(&experience.state, &target_q_values, LEARNING_RATE);
+            loss_sum += network.backpropagate_batch(&states, &target_q_values, Some(&sampled.weights), loss, optimizer);
+            loss_count += 1;
+            experiences.update_priorities(&sampled.slots, &td_errors);
+            target_network.update(&network);
+        }
 
-            }
+        if (episode + 1) % config.log_interval.max(1) == 0 && loss_count > 0 {
+            println!("Episode {}/{}: mean loss {:.5}, epsilon {:.3}", episode + 1, config.episodes, loss_sum / loss_count as f32, epsilon);
+            loss_sum = 0.0;
+            loss_count = 0;
         }
 
         // Decay epsilon after a full game (episode) as decay is optimized for episodes count
-        epsilon = epsilon * EPSILON_DECAY;
-        epsilon = epsilon.max(FINAL_EPSILON);
+        epsilon *= config.epsilon_decay;
+        epsilon = epsilon.max(config.final_epsilon);
     }
     Ok(network)
 }
 
-pub fn save_network(model: &NeuralNetwork, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+// A saved model: the network plus, if it came out of training, the config that produced it.
+// Files written before the config was embedded load with train_config None.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Checkpoint {
+    #[serde(flatten)]
+    pub network: NeuralNetwork,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub train_config: Option<TrainConfig>,
+}
+
+pub fn save_network(model: &NeuralNetwork, train_config: Option<&TrainConfig>, path: &str) -> Result<(), Box<dyn std::error::Error>> {
     let file = File::create(path)?;
     let writer = BufWriter::new(file);
-    serde_json::to_writer(writer, model)?;
+    let checkpoint = Checkpoint { network: model.clone(), train_config: train_config.cloned() };
+    serde_json::to_writer(writer, &checkpoint)?;
     Ok(())
 }
 
-pub fn load_network(path: &str, node_counts: &[usize], activations: &[Activation]) -> Result<NeuralNetwork, Box<dyn std::error::Error>> {
+pub fn load_checkpoint(path: &str) -> Result<Checkpoint, Box<dyn std::error::Error>> {
     let file = File::open(path)?;
     let reader = BufReader::new(file);
-    let model: NeuralNetwork = serde_json::from_reader(reader)?;
+    Ok(serde_json::from_reader(reader)?)
+}
+
+// An optimizer state with the config it was built from
+#[derive(Serialize, Deserialize)]
+pub struct OptimizerCheckpoint<O> {
+    pub config: OptimizerConfig,
+    pub optimizer: O,
+}
+
+pub fn save_optimizer(optimizer: &impl Serialize, config: &OptimizerConfig, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let file = File::create(path)?;
+    let writer = BufWriter::new(file);
+    serde_json::to_writer(writer, &OptimizerCheckpoint { config: *config, optimizer })?;
+    Ok(())
+}
+
+// Restores the moment buffers of an interrupted run so training resumes where it stopped. A state
+// saved for another config (e.g. a different learning rate) is refused, so config changes apply.
+pub fn load_optimizer<O: DeserializeOwned>(path: &str, config: &OptimizerConfig) -> Result<O, Box<dyn std::error::Error>> {
+    let file = File::open(path)?;
+    let reader = BufReader::new(file);
+    let checkpoint: OptimizerCheckpoint<O> = serde_json::from_reader(reader)?;
+    if checkpoint.config != *config {
+        return Err(format!("{} was saved for another optimizer config", path).into());
+    }
+    Ok(checkpoint.optimizer)
+}
+
+pub fn load_network(path: &str, node_counts: &[usize], activations: &[Activation]) -> Result<NeuralNetwork, Box<dyn std::error::Error>> {
+    let model = load_checkpoint(path)?.network;
+    let mismatch = || format!("{} does not fit layer sizes {:?}, it was trained for another game or board", path, node_counts);
+    if model.layers.len() + 1 != node_counts.len() {
+        return Err(mismatch().into());
+    }
     for ((wnd, layer), activation) in node_counts.windows(2).zip(&model.layers).zip(activations) {
-        if wnd[0] != layer.weights.len() || wnd[1] != layer.weights[0].len() || wnd[1] != layer.biases.len() || activation != &layer.activation {
-            return Err("Invalid model".into());
+        if wnd[0] != layer.input_size || wnd[1] != layer.output_size || activation != &layer.activation {
+            return Err(mismatch().into());
         }
     }
     Ok(model)
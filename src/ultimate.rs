@@ -0,0 +1,287 @@
+use crate::game::{Game, Outcome, Player, Symmetry, cell_char, transform_input};
+use std::fmt;
+
+// Cells per side of the whole board
+pub const SIZE: usize = 9;
+
+// The rows, columns and diagonals of a 3x3 grid, as indices row * 3 + col
+const LINES: [[usize; 3]; 8] = [
+    [0, 1, 2], [3, 4, 5], [6, 7, 8],
+    [0, 3, 6], [1, 4, 7], [2, 5, 8],
+    [0, 4, 8], [2, 4, 6],
+];
+
+// Ultimate tic-tac-toe: nine 3x3 sub-boards in a 3x3 grid. Where in its sub-board a move is made
+// decides the sub-board the opponent has to play in next. If that sub-board is already won or
+// full the opponent may play in any open one. Winning three sub-boards in a row wins the game.
+// Actions are the cells of the 9x9 board, row * 9 + col.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct UltimateTicTacToe {
+    cells: [Option<Player>; SIZE * SIZE],
+    // how every sub-board ended, None while open
+    sub_boards: [Option<Outcome>; 9],
+    // the sub-board the player to move is sent to, None for any open one
+    active: Option<usize>,
+    moves: usize,
+}
+
+impl Default for UltimateTicTacToe {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UltimateTicTacToe {
+    pub fn new() -> Self {
+        Self { cells: [None; SIZE * SIZE], sub_boards: [None; 9], active: None, moves: 0 }
+    }
+
+    pub fn cell(&self, row: usize, col: usize) -> Option<Player> {
+        self.cells[row * SIZE + col]
+    }
+
+    // How sub-board index (row * 3 + col of the sub-board) ended, None while open
+    pub fn sub_board(&self, index: usize) -> Option<Outcome> {
+        self.sub_boards[index]
+    }
+
+    pub fn active_sub_board(&self) -> Option<usize> {
+        self.active
+    }
+
+    // The sub-board the cell belongs to and the cell's position inside it
+    fn locate(action: usize) -> (usize, usize) {
+        let (row, col) = (action / SIZE, action % SIZE);
+        ((row / 3) * 3 + col / 3, (row % 3) * 3 + col % 3)
+    }
+
+    // Cell index of position inside sub-board
+    fn cell_index(sub_board: usize, position: usize) -> usize {
+        let row = (sub_board / 3) * 3 + position / 3;
+        let col = (sub_board % 3) * 3 + position % 3;
+        row * SIZE + col
+    }
+
+    // A sub-board may be played in while it is open, and when the previous move sent the player there
+    fn is_playable(&self, sub_board: usize) -> bool {
+        self.sub_boards[sub_board].is_none() && self.active.is_none_or(|active| active == sub_board)
+    }
+
+    pub fn winner(&self) -> Option<Player> {
+        line_winner(|index| match self.sub_boards[index] {
+            Some(Outcome::Win(player)) => Some(player),
+            _ => None,
+        })
+    }
+}
+
+// The player holding a whole line of the 3x3 grid
+fn line_winner(grid: impl Fn(usize) -> Option<Player>) -> Option<Player> {
+    LINES.iter()
+        .map(|line| line.map(&grid))
+        .find(|[a, b, c]| a.is_some() && a == b && b == c)
+        .and_then(|[a, _, _]| a)
+}
+
+impl Game for UltimateTicTacToe {
+    fn initial_state(&self) -> Self {
+        Self::new()
+    }
+
+    fn current_player(&self) -> Player {
+        if self.moves.is_multiple_of(2) { Player::X } else { Player::O }
+    }
+
+    fn legal_actions(&self) -> Vec<usize> {
+        if self.winner().is_some() {
+            return Vec::new();
+        }
+        (0..SIZE * SIZE).filter(|&action| self.is_legal(action)).collect()
+    }
+
+    fn is_legal(&self, action: usize) -> bool {
+        action < SIZE * SIZE && self.cells[action].is_none() && self.is_playable(Self::locate(action).0)
+    }
+
+    fn apply(&mut self, action: usize) -> Result<(), &'static str> {
+        if action >= SIZE * SIZE {
+            return Err("Invalid move: out of bounds");
+        }
+        if self.cells[action].is_some() {
+            return Err("Invalid move: cell already occupied");
+        }
+        let (sub_board, position) = Self::locate(action);
+        if !self.is_playable(sub_board) {
+            return Err("Invalid move: sub-board is not in play");
+        }
+
+        self.cells[action] = Some(self.current_player());
+        let sub_cells = |index| self.cells[Self::cell_index(sub_board, index)];
+        if let Some(winner) = line_winner(sub_cells) {
+            self.sub_boards[sub_board] = Some(Outcome::Win(winner));
+        } else if (0..9).all(|index| sub_cells(index).is_some()) {
+            self.sub_boards[sub_board] = Some(Outcome::Draw);
+        }
+        // a decided sub-board cannot take the opponent, they may go anywhere instead
+        self.active = self.sub_boards[position].is_none().then_some(position);
+        self.moves += 1;
+        Ok(())
+    }
+
+    fn outcome(&self) -> Option<Outcome> {
+        match self.winner() {
+            Some(winner) => Some(Outcome::Win(winner)),
+            None if self.sub_boards.iter().all(Option::is_some) => Some(Outcome::Draw),
+            None => None,
+        }
+    }
+
+    fn action_space(&self) -> usize {
+        SIZE * SIZE
+    }
+
+    // Three 9x9 planes, row-major: the player's marks, the opponent's marks and the cells of the
+    // sub-boards the player to move may play in
+    fn observation(&self, player: Player) -> Vec<f32> {
+        let cells = SIZE * SIZE;
+        let mut input = vec![0.0; 3 * cells];
+        for (index, &cell) in self.cells.iter().enumerate() {
+            match cell {
+                None => (),
+                Some(owner) if owner == player => input[index] = 1.0,
+                Some(_) => input[cells + index] = 1.0,
+            }
+            if self.is_playable(Self::locate(index).0) {
+                input[2 * cells + index] = 1.0;
+            }
+        }
+        input
+    }
+
+    // Rotating or mirroring the 9x9 board maps sub-boards onto sub-boards and the position
+    // inside them the same way, so the send rule is preserved
+    fn symmetries(&self) -> Vec<Symmetry> {
+        Symmetry::ALL.to_vec()
+    }
+
+    fn canonical_symmetry(&self) -> Symmetry {
+        let observation = self.observation(Player::X);
+        Symmetry::ALL.iter()
+            .map(|&symmetry| (self.transform_observation(&observation, symmetry), symmetry))
+            .min_by(|a, b| a.0.partial_cmp(&b.0).unwrap())
+            .unwrap()
+            .1
+    }
+
+    fn transform_observation(&self, observation: &[f32], symmetry: Symmetry) -> Vec<f32> {
+        transform_input(observation, symmetry, SIZE, SIZE)
+    }
+
+    fn transform_action(&self, action: usize, symmetry: Symmetry) -> usize {
+        symmetry.map_action(action, SIZE, SIZE)
+    }
+}
+
+// The 9x9 board with the sub-boards set apart, one line per row
+impl fmt::Display for UltimateTicTacToe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for row in 0..SIZE {
+            if row > 0 && row % 3 == 0 {
+                writeln!(f, "------+-------+------")?;
+            }
+            for col in 0..SIZE {
+                if col > 0 && col % 3 == 0 {
+                    write!(f, "| ")?;
+                }
+                write!(f, "{} ", cell_char(self.cell(row, col)))?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use rand::seq::SliceRandom;
+
+    // Plays moves given as (sub-board, position inside it)
+    fn play(game: &mut UltimateTicTacToe, moves: &[(usize, usize)]) {
+        for &(sub_board, position) in moves {
+            game.apply(UltimateTicTacToe::cell_index(sub_board, position)).unwrap();
+        }
+    }
+
+    fn legal_sub_boards(game: &UltimateTicTacToe) -> Vec<usize> {
+        let mut sub_boards: Vec<usize> = game.legal_actions().into_iter().map(|action| UltimateTicTacToe::locate(action).0).collect();
+        sub_boards.sort();
+        sub_boards.dedup();
+        sub_boards
+    }
+
+    #[test]
+    fn move_sends_the_opponent_to_the_matching_sub_board() {
+        let mut game = UltimateTicTacToe::new();
+        play(&mut game, &[(4, 2)]);
+        assert_eq!(game.active_sub_board(), Some(2));
+        assert_eq!(legal_sub_boards(&game), vec![2]);
+        assert_eq!(game.legal_actions().len(), 9);
+        assert_eq!(game.apply(UltimateTicTacToe::cell_index(3, 0)), Err("Invalid move: sub-board is not in play"));
+    }
+
+    #[test]
+    fn won_sub_board_releases_the_opponent() {
+        let mut game = UltimateTicTacToe::new();
+        // O takes the middle row of sub-board 0
+        play(&mut game, &[(0, 0), (0, 3), (3, 0), (0, 4), (4, 0), (0, 5)]);
+        assert_eq!(game.sub_board(0), Some(Outcome::Win(Player::O)));
+        assert_eq!(game.active_sub_board(), Some(5));
+
+        play(&mut game, &[(5, 0)]);
+        assert_eq!(game.active_sub_board(), None);
+        assert_eq!(legal_sub_boards(&game), (1..9).collect::<Vec<_>>());
+        assert_eq!(game.winner(), None);
+    }
+
+    #[test]
+    fn full_sub_board_is_a_draw_and_releases_the_opponent() {
+        let mut game = UltimateTicTacToe::new();
+        // X O X / X O O / O X -, X to move in sub-board 0
+        for (position, player) in [(0, Player::X), (1, Player::O), (2, Player::X), (3, Player::X), (4, Player::O), (5, Player::O), (6, Player::O), (7, Player::X)] {
+            game.cells[UltimateTicTacToe::cell_index(0, position)] = Some(player);
+        }
+        game.moves = 8;
+        game.active = Some(0);
+
+        play(&mut game, &[(0, 8)]);
+        assert_eq!(game.sub_board(0), Some(Outcome::Draw));
+        assert_eq!(game.active_sub_board(), Some(8));
+
+        play(&mut game, &[(8, 0)]);
+        assert_eq!(game.active_sub_board(), None);
+        assert!(!legal_sub_boards(&game).contains(&0));
+        assert_eq!(game.outcome(), None);
+    }
+
+    #[test]
+    fn action_mask_matches_legal_actions() {
+        let mut rng = StdRng::seed_from_u64(5);
+        for _ in 0..50 {
+            let mut game = UltimateTicTacToe::new();
+            while game.outcome().is_none() {
+                let legal = game.legal_actions();
+                let mask = game.action_mask();
+                assert_eq!(mask.len(), game.action_space());
+                for (action, &allowed) in mask.iter().enumerate() {
+                    assert_eq!(allowed, legal.contains(&action), "action {}", action);
+                    assert_eq!(game.is_legal(action), allowed, "action {}", action);
+                }
+                game.apply(*legal.choose(&mut rng).unwrap()).unwrap();
+            }
+            assert!(game.legal_actions().is_empty());
+        }
+    }
+}